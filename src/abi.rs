@@ -0,0 +1,138 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::fs;
+
+use goblin::elf::header::{machine_to_str, EM_386, EM_AARCH64, EM_ARM, EM_X86_64};
+use log::trace;
+use thiserror::Error;
+
+use crate::profile::TargetProfile;
+use crate::FileEntry;
+
+/// The architecture targeted if the target profile doesn't give one
+pub const DEFAULT_ARCHITECTURE: &str = "x86_64";
+/// The dynamic loader used if the target profile doesn't give one
+pub const DEFAULT_INTERPRETER: &str = "/lib64/ld-linux-x86-64.so.2";
+
+#[derive(Debug, Error)]
+pub enum AbiError {
+    #[error("unknown target architecture {0}")]
+    UnknownArchitecture(String),
+    #[error("IO error while checking {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("{}", .0.join("; "))]
+    Mismatches(Vec<String>),
+}
+
+/// The architectures we know how to check for, with their ELF machine
+/// and whether they are 64 bit
+const ARCHITECTURES: &[(&str, u16, bool)] = &[
+    ("x86_64", EM_X86_64, true),
+    ("i386", EM_386, false),
+    ("aarch64", EM_AARCH64, true),
+    ("arm", EM_ARM, false),
+];
+
+fn architecture_name(machine: u16) -> &'static str {
+    ARCHITECTURES
+        .iter()
+        .find(|(_, m, _)| *m == machine)
+        .map(|(name, _, _)| *name)
+        .unwrap_or_else(|| machine_to_str(machine))
+}
+
+fn class_name(is_64: bool) -> &'static str {
+    if is_64 {
+        "64 bit"
+    } else {
+        "32 bit"
+    }
+}
+
+/// The machine, ELF class and dynamic loader bundled ELF files must use
+#[derive(Clone, Debug)]
+pub struct TargetAbi {
+    pub architecture: String,
+    pub machine: u16,
+    pub is_64: bool,
+    pub interpreter: String,
+}
+
+impl TargetAbi {
+    /// The ABI a target profile expects, or that of the VCS if there is
+    /// no profile
+    pub fn for_profile(profile: Option<&TargetProfile>) -> Result<TargetAbi, AbiError> {
+        let architecture = profile
+            .and_then(|p| p.architecture.as_deref())
+            .unwrap_or(DEFAULT_ARCHITECTURE);
+        let (_, machine, is_64) = ARCHITECTURES
+            .iter()
+            .find(|(name, _, _)| *name == architecture)
+            .ok_or_else(|| AbiError::UnknownArchitecture(architecture.to_string()))?;
+        Ok(TargetAbi {
+            architecture: architecture.to_string(),
+            machine: *machine,
+            is_64: *is_64,
+            interpreter: profile
+                .and_then(|p| p.interpreter.clone())
+                .unwrap_or_else(|| DEFAULT_INTERPRETER.to_string()),
+        })
+    }
+
+    /// Describe how a file doesn't match this ABI, if it doesn't. Files
+    /// which aren't ELF files, like scripts, always match.
+    fn check(&self, entry: &FileEntry) -> Result<Option<String>, AbiError> {
+        let buf = fs::read(&entry.location).map_err(|e| AbiError::Io(entry.name.clone(), e))?;
+        let elf = match goblin::Object::parse(&buf) {
+            Ok(goblin::Object::Elf(elf)) => elf,
+            _ => return Ok(None),
+        };
+        let name = format!("{} ({})", entry.name, entry.location.to_string_lossy());
+
+        if elf.header.e_machine != self.machine {
+            return Ok(Some(format!(
+                "{} is built for {}, but the target is {}",
+                name,
+                architecture_name(elf.header.e_machine),
+                self.architecture
+            )));
+        }
+        if elf.is_64 != self.is_64 {
+            return Ok(Some(format!(
+                "{} is {}, but the target is {}",
+                name,
+                class_name(elf.is_64),
+                class_name(self.is_64)
+            )));
+        }
+        match elf.interpreter {
+            Some(interpreter) if interpreter != self.interpreter => Ok(Some(format!(
+                "{} uses the dynamic loader {}, but the target's is {}",
+                name, interpreter, self.interpreter
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Check every ELF file among the given entries matches this ABI
+    pub fn check_all<'a, I>(&self, entries: I) -> Result<(), AbiError>
+    where
+        I: IntoIterator<Item = &'a FileEntry>,
+    {
+        let mut mismatches = Vec::new();
+        for entry in entries {
+            trace!("Checking the ABI of {}", entry.name);
+            if let Some(mismatch) = self.check(entry)? {
+                mismatches.push(mismatch);
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(AbiError::Mismatches(mismatches))
+        }
+    }
+}
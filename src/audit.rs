@@ -0,0 +1,176 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use goblin::elf::dynamic::{DF_1_NOW, DF_BIND_NOW, DT_BIND_NOW};
+use goblin::elf::header::ET_EXEC;
+use goblin::elf::program_header::{PF_X, PT_GNU_RELRO, PT_GNU_STACK};
+use goblin::elf::Elf;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zip::ZipArchive;
+
+use crate::ldcache::ELF_MAGIC;
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("unable to open bundle: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unable to read bundle archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// A hardening or quality problem found in an ELF file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Finding {
+    /// An executable that isn't position independent
+    NotPie,
+    /// Relocations are only partly read-only after loading
+    PartialRelro,
+    /// Relocations remain writable after loading
+    NoRelro,
+    /// The stack is executable
+    ExecutableStack,
+    /// Nothing was built with a stack protector
+    NoStackProtector,
+    /// The code itself needs relocating, so can't be shared
+    TextRelocations,
+    /// Debug information was left in
+    DebugInfo,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Finding::NotPie => "not position independent",
+            Finding::PartialRelro => "only partial RELRO",
+            Finding::NoRelro => "no RELRO",
+            Finding::ExecutableStack => "executable stack",
+            Finding::NoStackProtector => "no stack protector",
+            Finding::TextRelocations => "text relocations",
+            Finding::DebugInfo => "contains debug info",
+        })
+    }
+}
+
+/// What auditing a single ELF file found
+#[derive(Clone, Debug, Serialize)]
+pub struct ElfAudit {
+    /// The file's name in the bundle
+    pub name: String,
+    pub findings: Vec<Finding>,
+}
+
+/// Whether an ELF file still has DWARF debug info in it
+pub(crate) fn has_debug_info(elf: &Elf) -> bool {
+    elf.section_headers.iter().any(|sh| {
+        matches!(
+            elf.shdr_strtab.get_at(sh.sh_name),
+            Some(".debug_info") | Some(".zdebug_info")
+        )
+    })
+}
+
+/// Audit an ELF file held in memory, returning `None` if it isn't one
+pub fn audit_elf(name: &str, buf: &[u8]) -> Option<ElfAudit> {
+    let elf = Elf::parse(buf).ok()?;
+    let mut findings = Vec::new();
+
+    // Only executables (those requesting a loader) can be PIE
+    if elf.interpreter.is_some() && elf.header.e_type == ET_EXEC {
+        findings.push(Finding::NotPie);
+    }
+
+    let bind_now = matches!(
+        elf.dynamic,
+        Some(ref d) if d.info.flags & DF_BIND_NOW != 0
+            || d.info.flags_1 & DF_1_NOW != 0
+            || d.dyns.iter().any(|entry| entry.d_tag == DT_BIND_NOW)
+    );
+    if !elf
+        .program_headers
+        .iter()
+        .any(|ph| ph.p_type == PT_GNU_RELRO)
+    {
+        findings.push(Finding::NoRelro);
+    } else if !bind_now {
+        findings.push(Finding::PartialRelro);
+    }
+
+    // Without PT_GNU_STACK, the loader makes the stack executable
+    match elf
+        .program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_GNU_STACK)
+    {
+        Some(ph) if ph.p_flags & PF_X == 0 => {}
+        _ => findings.push(Finding::ExecutableStack),
+    }
+
+    // Each symbol table has its own string table
+    let stack_protected = elf
+        .dynsyms
+        .iter()
+        .filter_map(|sym| elf.dynstrtab.get_at(sym.st_name))
+        .chain(
+            elf.syms
+                .iter()
+                .filter_map(|sym| elf.strtab.get_at(sym.st_name)),
+        )
+        .any(|name| name == "__stack_chk_fail" || name == "__stack_chk_guard");
+    if !stack_protected {
+        findings.push(Finding::NoStackProtector);
+    }
+
+    if matches!(elf.dynamic, Some(ref d) if d.info.textrel) {
+        findings.push(Finding::TextRelocations);
+    }
+
+    if has_debug_info(&elf) {
+        findings.push(Finding::DebugInfo);
+    }
+
+    Some(ElfAudit {
+        name: name.to_string(),
+        findings,
+    })
+}
+
+/// Read every ELF file in a bundle, with its name in the bundle. Only
+/// the start of other files is read.
+pub(crate) fn elf_files<R: Read + Seek>(
+    za: &mut ZipArchive<R>,
+) -> Result<Vec<(String, Vec<u8>)>, zip::result::ZipError> {
+    let mut elves = Vec::new();
+    for i in 0..za.len() {
+        let mut zf = za.by_index(i)?;
+        if !zf.is_file() {
+            continue;
+        }
+        let mut buf = Vec::new();
+        (&mut zf)
+            .take(ELF_MAGIC.len() as u64)
+            .read_to_end(&mut buf)?;
+        if buf != ELF_MAGIC {
+            continue;
+        }
+        zf.read_to_end(&mut buf)?;
+        elves.push((zf.name().to_string(), buf));
+    }
+    Ok(elves)
+}
+
+/// Audit every ELF file in a bundle
+pub fn audit_bundle<P: AsRef<Path>>(path: P) -> Result<Vec<ElfAudit>, AuditError> {
+    let mut za = ZipArchive::new(File::open(path)?)?;
+    Ok(elf_files(&mut za)?
+        .iter()
+        .filter_map(|(name, buf)| audit_elf(name, buf))
+        .collect())
+}
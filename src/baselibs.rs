@@ -0,0 +1,672 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::ldcache::{self, LdError};
+
+/// The directories the dynamic loader always searches, in order
+const DEFAULT_LIBRARY_DIRS: &[&str] = &[
+    "/lib/x86_64-linux-gnu",
+    "/usr/lib/x86_64-linux-gnu",
+    "/lib64",
+    "/usr/lib64",
+    "/lib",
+    "/usr/lib",
+];
+
+/// How many symlinks we'll follow before giving up on a path
+const MAX_SYMLINKS: usize = 32;
+
+const OLD_CACHE_MAGIC: &[u8] = b"ld.so-1.7.0";
+const NEW_CACHE_MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+const OLD_HEADER_LEN: usize = 16;
+const OLD_ENTRY_LEN: usize = 12;
+const NEW_HEADER_LEN: usize = 48;
+const NEW_ENTRY_LEN: usize = 24;
+/// The cache flag for a little endian cache
+const CACHE_LITTLE_ENDIAN: u8 = 2;
+/// The entry flags for an x86_64 libc6 library
+const FLAG_X8664_LIBC6: i32 = 0x0303;
+
+/// The formats a base library listing can be stored in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingFormat {
+    /// One library per line, for easy review
+    Text,
+    /// A JSON document, for other tools
+    Json,
+    /// A binary `ld.so.cache`, as used on devices
+    Cache,
+}
+
+impl FromStr for ListingFormat {
+    type Err = LdError;
+
+    fn from_str(s: &str) -> Result<Self, LdError> {
+        match s {
+            "text" => Ok(ListingFormat::Text),
+            "json" => Ok(ListingFormat::Json),
+            "cache" => Ok(ListingFormat::Cache),
+            _ => Err(LdError::BadListing(format!("unknown format {}", s))),
+        }
+    }
+}
+
+/// A shared library provided by a base system
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BaseLibrary {
+    /// The name the library is found by
+    pub soname: String,
+    /// Where the library is installed on the base system
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The library's own version, like `6.0.28` for `libstdc++.so.6`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The symbol versions the library defines
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub symbol_versions: BTreeSet<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Listing {
+    libraries: Vec<BaseLibrary>,
+}
+
+/// The libraries a base system provides, which can be read from and
+/// written to a binary `ld.so.cache`, or a reviewable text or JSON
+/// listing.
+#[derive(Clone, Debug, Default)]
+pub struct BaseLibraries {
+    libraries: BTreeMap<String, BaseLibrary>,
+}
+
+impl BaseLibraries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a library, unless one with the same soname is already present
+    pub fn insert(&mut self, library: BaseLibrary) {
+        self.libraries
+            .entry(library.soname.clone())
+            .or_insert(library);
+    }
+
+    pub fn contains(&self, soname: &str) -> bool {
+        self.libraries.contains_key(soname)
+    }
+
+    pub fn get(&self, soname: &str) -> Option<&BaseLibrary> {
+        self.libraries.get(soname)
+    }
+
+    /// All the libraries, ordered by soname
+    pub fn iter(&self) -> impl Iterator<Item = &BaseLibrary> {
+        self.libraries.values()
+    }
+
+    /// Read libraries in any supported format, detecting which it is
+    pub fn parse(buf: &[u8]) -> Result<Self, LdError> {
+        if buf.starts_with(OLD_CACHE_MAGIC) || buf.starts_with(NEW_CACHE_MAGIC) {
+            Self::from_cache(buf)
+        } else if buf.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+            Ok(Self::from_json(buf)?)
+        } else {
+            Self::from_text(
+                std::str::from_utf8(buf).map_err(|e| LdError::BadListing(e.to_string()))?,
+            )
+        }
+    }
+
+    /// Write libraries in the given format
+    pub fn to_format(&self, format: ListingFormat) -> Result<Vec<u8>, LdError> {
+        Ok(match format {
+            ListingFormat::Text => self.to_text().into_bytes(),
+            ListingFormat::Json => (self.to_json()? + "\n").into_bytes(),
+            ListingFormat::Cache => self.to_cache(),
+        })
+    }
+
+    pub fn from_json(buf: &[u8]) -> serde_json::Result<Self> {
+        let listing: Listing = serde_json::from_slice(buf)?;
+        let mut res = Self::new();
+        for library in listing.libraries {
+            res.insert(library);
+        }
+        Ok(res)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&Listing {
+            libraries: self.libraries.values().cloned().collect(),
+        })
+    }
+
+    /// Read the text format, which has one library per line: its soname,
+    /// followed by optional `path=`, `version=` and `symbols=` fields,
+    /// the last holding a comma separated list of symbol versions.
+    /// Blank lines and anything following a `#` are ignored.
+    pub fn from_text(text: &str) -> Result<Self, LdError> {
+        let mut res = Self::new();
+        for (n, line) in text.lines().enumerate() {
+            let mut fields = line
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace();
+            let soname = match fields.next() {
+                Some(soname) => soname.to_string(),
+                None => continue,
+            };
+            let mut library = BaseLibrary {
+                soname,
+                ..Default::default()
+            };
+            for field in fields {
+                match field.split_once('=') {
+                    Some(("path", path)) => library.path = Some(path.to_string()),
+                    Some(("version", version)) => library.version = Some(version.to_string()),
+                    Some(("symbols", symbols)) => library.symbol_versions.extend(
+                        symbols
+                            .split(',')
+                            .filter(|s| !s.is_empty())
+                            .map(str::to_string),
+                    ),
+                    _ => {
+                        return Err(LdError::BadListing(format!(
+                            "unknown field {} on line {}",
+                            field,
+                            n + 1
+                        )))
+                    }
+                }
+            }
+            res.insert(library);
+        }
+        Ok(res)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut res =
+            String::from("# Base system libraries: soname [path=] [version=] [symbols=]\n");
+        for library in self.libraries.values() {
+            res.push_str(&library.soname);
+            if let Some(ref path) = library.path {
+                let _ = write!(res, " path={}", path);
+            }
+            if let Some(ref version) = library.version {
+                let _ = write!(res, " version={}", version);
+            }
+            if !library.symbol_versions.is_empty() {
+                let symbols = library
+                    .symbol_versions
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>();
+                let _ = write!(res, " symbols={}", symbols.join(","));
+            }
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Read a binary `ld.so.cache`, in either the old or new format, or
+    /// both combined. A cache records only sonames and paths.
+    pub fn from_cache(buf: &[u8]) -> Result<Self, LdError> {
+        let mut res = Self::new();
+        let (new_start, old) = if buf.starts_with(OLD_CACHE_MAGIC) {
+            let nlibs = read_u32(buf, OLD_CACHE_MAGIC.len() + 1)? as usize;
+            let end = OLD_HEADER_LEN + nlibs * OLD_ENTRY_LEN;
+            // A new format cache may follow the old entries, 8 byte aligned
+            let new_start = (end + 7) & !7;
+            if buf.len() >= new_start && buf[new_start..].starts_with(NEW_CACHE_MAGIC) {
+                (Some(new_start), None)
+            } else {
+                (None, Some((nlibs, end)))
+            }
+        } else {
+            (Some(0), None)
+        };
+
+        if let Some(start) = new_start {
+            let nlibs = read_u32(buf, start + NEW_CACHE_MAGIC.len())? as usize;
+            for i in 0..nlibs {
+                let entry = start + NEW_HEADER_LEN + i * NEW_ENTRY_LEN;
+                res.insert_cache_entry(
+                    buf,
+                    start,
+                    read_u32(buf, entry + 4)?,
+                    read_u32(buf, entry + 8)?,
+                )?;
+            }
+        } else if let Some((nlibs, strings)) = old {
+            for i in 0..nlibs {
+                let entry = OLD_HEADER_LEN + i * OLD_ENTRY_LEN;
+                res.insert_cache_entry(
+                    buf,
+                    strings,
+                    read_u32(buf, entry + 4)?,
+                    read_u32(buf, entry + 8)?,
+                )?;
+            }
+        }
+        Ok(res)
+    }
+
+    fn insert_cache_entry(
+        &mut self,
+        buf: &[u8],
+        strings: usize,
+        key: u32,
+        value: u32,
+    ) -> Result<(), LdError> {
+        self.insert(BaseLibrary {
+            soname: read_str(buf, strings + key as usize)?,
+            path: Some(read_str(buf, strings + value as usize)?),
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    /// Write a new format binary `ld.so.cache`, as read by glibc 2.32
+    /// and later. Libraries without a path are omitted, and versions and
+    /// symbol versions are lost.
+    pub fn to_cache(&self) -> Vec<u8> {
+        let mut entries = self
+            .libraries
+            .values()
+            .filter_map(|l| l.path.as_ref().map(|p| (l.soname.as_str(), p.as_str())))
+            .collect::<Vec<_>>();
+        // The loader binary searches the cache, expecting this order
+        entries.sort_by(|a, b| libcmp(b.0, a.0));
+
+        let strings_start = NEW_HEADER_LEN + entries.len() * NEW_ENTRY_LEN;
+        let mut strings = Vec::new();
+        let mut table = Vec::new();
+        for (soname, path) in entries.iter() {
+            let key = strings_start + strings.len();
+            strings.extend_from_slice(soname.as_bytes());
+            strings.push(0);
+            let value = strings_start + strings.len();
+            strings.extend_from_slice(path.as_bytes());
+            strings.push(0);
+
+            table.extend_from_slice(&FLAG_X8664_LIBC6.to_le_bytes());
+            table.extend_from_slice(&(key as u32).to_le_bytes());
+            table.extend_from_slice(&(value as u32).to_le_bytes());
+            table.extend_from_slice(&0u32.to_le_bytes());
+            table.extend_from_slice(&0u64.to_le_bytes());
+        }
+
+        let mut res = Vec::with_capacity(strings_start + strings.len());
+        res.extend_from_slice(NEW_CACHE_MAGIC);
+        res.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        res.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        res.push(CACHE_LITTLE_ENDIAN);
+        res.extend_from_slice(&[0; 3]);
+        // No extensions, and three unused words
+        res.extend_from_slice(&[0; 16]);
+        res.extend_from_slice(&table);
+        res.extend_from_slice(&strings);
+        res
+    }
+
+    /// Find every shared library the dynamic loader would find on the
+    /// root filesystem at `root`, as `ldconfig` would. The directories
+    /// searched are those in the root's `/etc/ld.so.conf`, followed by the
+    /// default library directories.
+    pub fn scan_rootfs<P: AsRef<Path>>(root: P) -> Result<Self, LdError> {
+        let root = root.as_ref();
+        let mut dirs = Vec::new();
+        read_ld_so_conf(root, Path::new("/etc/ld.so.conf"), &mut dirs)?;
+        dirs.extend(DEFAULT_LIBRARY_DIRS.iter().map(PathBuf::from));
+
+        let mut res = Self::new();
+        let mut seen_dirs = BTreeSet::new();
+        for dir in dirs {
+            let host_dir = match resolve_in_root(root, &dir) {
+                Some(d) if d.is_dir() => d,
+                _ => continue,
+            };
+            if !seen_dirs.insert(host_dir.clone()) {
+                continue;
+            }
+            trace!("Scanning {} for libraries", dir.to_string_lossy());
+            let mut entries = fs::read_dir(&host_dir)?
+                .filter_map(Result::ok)
+                .map(|e| e.file_name())
+                .collect::<Vec<_>>();
+            entries.sort();
+            for name in entries {
+                let path = dir.join(&name);
+                let host_path = match resolve_in_root(root, &path) {
+                    Some(p) if p.is_file() => p,
+                    _ => continue,
+                };
+                if let Some(library) = read_library(&host_path, &dir, &name.to_string_lossy()) {
+                    trace!(" - {} at {}", library.soname, path.to_string_lossy());
+                    res.insert(library);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// The version a library's real file is named for, like `6.0.28` for
+/// `libstdc++.so.6.0.28`, if its name extends the soname
+pub(crate) fn library_version(path: &Path, soname: &str) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    if name.len() <= soname.len() || !name.starts_with(soname) {
+        return None;
+    }
+    let start = name.find(".so.")? + 4;
+    Some(name[start..].to_string())
+}
+
+/// Read a shared library, returning `None` for anything that isn't one
+fn read_library(host_path: &Path, dir: &Path, file_name: &str) -> Option<BaseLibrary> {
+    let buf = fs::read(host_path).ok()?;
+    let elf = match goblin::Object::parse(&buf) {
+        Ok(goblin::Object::Elf(elf)) => elf,
+        _ => return None,
+    };
+    if elf.header.e_type != goblin::elf::header::ET_DYN || elf.dynamic.is_none() {
+        return None;
+    }
+    let soname = elf.soname.unwrap_or(file_name).to_string();
+
+    let symbol_versions = ldcache::defined_versions(&elf);
+
+    let version = library_version(host_path, &soname);
+
+    Some(BaseLibrary {
+        path: Some(dir.join(&soname).to_string_lossy().to_string()),
+        soname,
+        version,
+        symbol_versions,
+    })
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32, LdError> {
+    buf.get(offset..offset + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(u32::from_le_bytes)
+        .ok_or_else(|| LdError::BadListing("truncated ld.so.cache".to_string()))
+}
+
+fn read_str(buf: &[u8], offset: usize) -> Result<String, LdError> {
+    let bytes = buf
+        .get(offset..)
+        .and_then(|b| b.split(|c| *c == 0).next())
+        .ok_or_else(|| LdError::BadListing("bad string in ld.so.cache".to_string()))?;
+    Ok(String::from_utf8_lossy(bytes).to_string())
+}
+
+/// Compare library names as glibc's `_dl_cache_libcmp` does: runs of
+/// digits compare as numbers, and a digit sorts above anything else
+fn libcmp(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() {
+        let (ca, cb) = (a[i], b.get(j).copied().unwrap_or(0));
+        if ca.is_ascii_digit() {
+            if !cb.is_ascii_digit() {
+                return Ordering::Greater;
+            }
+            let (start_i, start_j) = (i, j);
+            while i < a.len() && a[i].is_ascii_digit() {
+                i += 1;
+            }
+            while j < b.len() && b[j].is_ascii_digit() {
+                j += 1;
+            }
+            let parse = |digits: &[u8]| {
+                digits.iter().fold(0u64, |v, d| {
+                    v.saturating_mul(10).saturating_add(u64::from(d - b'0'))
+                })
+            };
+            match parse(&a[start_i..i]).cmp(&parse(&b[start_j..j])) {
+                Ordering::Equal => {}
+                other => return other,
+            }
+        } else if cb.is_ascii_digit() {
+            return Ordering::Less;
+        } else if ca != cb {
+            // glibc compares plain chars, which are signed on x86
+            return (ca as i8).cmp(&(cb as i8));
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+    0.cmp(&(b.get(j).copied().unwrap_or(0) as i8))
+}
+
+/// Find where a path inside `root` really is on the host, following
+/// symlinks as though `root` were the filesystem root.
+fn resolve_in_root(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut pending = path
+        .components()
+        .rev()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect::<Vec<_>>();
+    let mut resolved = PathBuf::from("/");
+    let mut links = 0;
+    while let Some(part) = pending.pop() {
+        match Path::new(&part).components().next() {
+            Some(Component::RootDir) | Some(Component::CurDir) | None => continue,
+            Some(Component::ParentDir) => {
+                resolved.pop();
+                continue;
+            }
+            _ => {}
+        }
+        let candidate = resolved.join(&part);
+        let host = root.join(candidate.strip_prefix("/").ok()?);
+        match fs::read_link(&host) {
+            Ok(target) => {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return None;
+                }
+                if target.is_absolute() {
+                    resolved = PathBuf::from("/");
+                }
+                pending.extend(
+                    target
+                        .components()
+                        .rev()
+                        .map(|c| c.as_os_str().to_os_string()),
+                );
+            }
+            Err(_) => resolved = candidate,
+        }
+    }
+    Some(root.join(resolved.strip_prefix("/").ok()?))
+}
+
+/// Collect the library directories listed in an `ld.so.conf` inside
+/// `root`, following `include` directives.
+fn read_ld_so_conf(root: &Path, conf: &Path, dirs: &mut Vec<PathBuf>) -> Result<(), LdError> {
+    let host_conf = match resolve_in_root(root, conf) {
+        Some(c) if c.is_file() => c,
+        _ => return Ok(()),
+    };
+    for line in fs::read_to_string(host_conf)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            let pattern = Path::new(pattern.trim());
+            let pattern = if pattern.is_absolute() {
+                pattern.to_path_buf()
+            } else {
+                conf.parent()
+                    .unwrap_or_else(|| Path::new("/"))
+                    .join(pattern)
+            };
+            for include in expand_include(root, &pattern)? {
+                read_ld_so_conf(root, &include, dirs)?;
+            }
+        } else if !line.is_empty() {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+    Ok(())
+}
+
+/// Expand an include pattern with at most one `*` in its final component
+fn expand_include(root: &Path, pattern: &Path) -> Result<Vec<PathBuf>, LdError> {
+    let (dir, name) = match (pattern.parent(), pattern.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy().to_string()),
+        _ => return Ok(Vec::new()),
+    };
+    let (prefix, suffix) = match name.split_once('*') {
+        Some(parts) => parts,
+        None => return Ok(vec![pattern.to_path_buf()]),
+    };
+    let host_dir = match resolve_in_root(root, dir) {
+        Some(d) if d.is_dir() => d,
+        _ => return Ok(Vec::new()),
+    };
+    let mut res = fs::read_dir(host_dir)?
+        .filter_map(Result::ok)
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|n| n.starts_with(prefix) && n.ends_with(suffix))
+        .map(|n| dir.join(n))
+        .collect::<Vec<_>>();
+    res.sort();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sonames in a new format cache, in the order they're stored,
+    /// with consecutive duplicates dropped
+    fn cache_order(buf: &[u8]) -> Vec<String> {
+        let start = buf
+            .windows(NEW_CACHE_MAGIC.len())
+            .position(|w| w == NEW_CACHE_MAGIC)
+            .unwrap();
+        let nlibs = read_u32(buf, start + NEW_CACHE_MAGIC.len()).unwrap() as usize;
+        let mut sonames: Vec<String> = Vec::new();
+        for i in 0..nlibs {
+            let key = read_u32(buf, start + NEW_HEADER_LEN + i * NEW_ENTRY_LEN + 4).unwrap();
+            let soname = read_str(buf, start + key as usize).unwrap();
+            if sonames.last() != Some(&soname) {
+                sonames.push(soname);
+            }
+        }
+        sonames
+    }
+
+    fn sample() -> BaseLibraries {
+        let mut libs = BaseLibraries::new();
+        libs.insert(BaseLibrary {
+            soname: "libstdc++.so.6".to_string(),
+            path: Some("/usr/lib/x86_64-linux-gnu/libstdc++.so.6".to_string()),
+            version: Some("6.0.28".to_string()),
+            symbol_versions: ["GLIBCXX_3.4", "GLIBCXX_3.4.28", "CXXABI_1.3"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        });
+        libs.insert(BaseLibrary {
+            soname: "libz.so.1".to_string(),
+            path: Some("/lib/x86_64-linux-gnu/libz.so.1".to_string()),
+            ..Default::default()
+        });
+        libs.insert(BaseLibrary {
+            soname: "libnopath.so.2".to_string(),
+            ..Default::default()
+        });
+        libs
+    }
+
+    fn assert_same(a: &BaseLibraries, b: &BaseLibraries) {
+        let a = a.iter().collect::<Vec<_>>();
+        let b = b.iter().collect::<Vec<_>>();
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert_eq!(a.soname, b.soname);
+            assert_eq!(a.path, b.path);
+            assert_eq!(a.version, b.version);
+            assert_eq!(a.symbol_versions, b.symbol_versions);
+        }
+    }
+
+    #[test]
+    fn libcmp_matches_glibc() {
+        // A digit sorts above anything else
+        assert_eq!(libcmp("libz3.so.4", "libzstd.so.1"), Ordering::Greater);
+        assert_eq!(libcmp("libzstd.so.1", "libz3.so.4"), Ordering::Less);
+        // Runs of digits compare as numbers
+        assert_eq!(libcmp("libfoo.so.10", "libfoo.so.9"), Ordering::Greater);
+        assert_eq!(libcmp("libfoo.so.010", "libfoo.so.10"), Ordering::Equal);
+        // A prefix sorts first
+        assert_eq!(libcmp("libfoo.so", "libfoo.so.1"), Ordering::Less);
+        assert_eq!(libcmp("libfoo.so.1", "libfoo.so"), Ordering::Greater);
+        assert_eq!(libcmp("libc.so.6", "libc.so.6"), Ordering::Equal);
+        assert_eq!(libcmp("liba.so", "libb.so"), Ordering::Less);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let libs = sample();
+        let text = libs.to_text();
+        assert_same(&libs, &BaseLibraries::from_text(&text).unwrap());
+        assert_same(&libs, &BaseLibraries::parse(text.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn text_rejects_unknown_fields() {
+        assert!(BaseLibraries::from_text("libz.so.1 colour=blue").is_err());
+        let libs = BaseLibraries::from_text("# comment\n\nlibz.so.1 # trailing\n").unwrap();
+        assert!(libs.contains("libz.so.1"));
+        assert_eq!(libs.iter().count(), 1);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let libs = sample();
+        let json = libs.to_format(ListingFormat::Json).unwrap();
+        assert_same(&libs, &BaseLibraries::parse(&json).unwrap());
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let libs = sample();
+        let cache = BaseLibraries::parse(&libs.to_cache()).unwrap();
+        // A cache only records sonames and paths
+        let sonames = cache.iter().map(|l| l.soname.as_str()).collect::<Vec<_>>();
+        assert_eq!(sonames, ["libstdc++.so.6", "libz.so.1"]);
+        for library in cache.iter() {
+            assert_eq!(library.path, libs.get(&library.soname).unwrap().path);
+            assert!(library.version.is_none());
+            assert!(library.symbol_versions.is_empty());
+        }
+    }
+
+    #[test]
+    fn shipped_cache_order_survives_conversion() {
+        let shipped = include_bytes!("../ld.so.cache.vcs");
+        let libs = BaseLibraries::parse(shipped).unwrap();
+        let converted = libs.to_cache();
+        assert_eq!(cache_order(shipped), cache_order(&converted));
+        assert_same(&libs, &BaseLibraries::parse(&converted).unwrap());
+    }
+}
@@ -3,19 +3,367 @@
  *
  * SPDX-License-Identifier: MIT
  */
-use anyhow::Result;
-use bundle_gen::generate::generate;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use bundle_gen::audit;
+use bundle_gen::baselibs::{BaseLibraries, ListingFormat};
+use bundle_gen::generate::{Generator, OutputOptions};
+use bundle_gen::graph::GraphFormat;
+use bundle_gen::ldcache::ResolveOptions;
+use bundle_gen::profile::TargetProfile;
 use structopt::StructOpt;
 
+/// The most chains `why` prints for each library
+const MAX_WHY_CHAINS: usize = 100;
+
+#[derive(Debug)]
+enum ReportFormat {
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(anyhow!("Unknown report format {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
+enum ProfileCommand {
+    #[structopt(
+        about = "Create a base library listing from a VCS root filesystem.",
+        long_about = "Create a base library listing from a mounted or extracted VCS root filesystem, recording the soname, path and symbol versions of every shared library the dynamic loader would find there. The listing can be used as a profile's BaseLibraries, or with --base-profile."
+    )]
+    Create {
+        #[structopt(parse(from_os_str), help = "The root filesystem to scan.")]
+        rootfs: PathBuf,
+        #[structopt(
+            long,
+            default_value = "json",
+            help = "The format to write: text, json or cache."
+        )]
+        format: ListingFormat,
+        #[structopt(
+            long,
+            short,
+            parse(from_os_str),
+            help = "The file to write the listing to, instead of stdout."
+        )]
+        output: Option<PathBuf>,
+    },
+    #[structopt(
+        about = "Convert a base library listing between formats.",
+        long_about = "Convert a base library listing between the text, JSON and binary ld.so.cache formats. The input format is detected automatically. A binary ld.so.cache records only sonames and paths, so library versions and symbol versions are lost when converting to it."
+    )]
+    Convert {
+        #[structopt(parse(from_os_str), help = "The listing or ld.so.cache to convert.")]
+        input: PathBuf,
+        #[structopt(long, help = "The format to write: text, json or cache.")]
+        format: ListingFormat,
+        #[structopt(
+            long,
+            short,
+            parse(from_os_str),
+            help = "The file to write the listing to, instead of stdout."
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "Manage target profiles.")]
+    Profile(ProfileCommand),
+    #[structopt(
+        about = "Explain why a library would be in a bundle.",
+        long_about = "Run the build and resolve dependencies, then print every chain of DT_NEEDED entries leading from a listed executable, library or extra ELF file to the given library."
+    )]
+    Why {
+        #[structopt(help = "The soname of the library, like libicudata.so.70.")]
+        soname: String,
+        #[structopt(name = "FILE")]
+        specification: String,
+    },
+    #[structopt(
+        about = "Export the dependency graph of a bundle.",
+        long_about = "Run the build and resolve dependencies, then write the graph of DT_NEEDED entries between the ELF files involved, marking each as an own file, bundled, provided by the base system, or missing."
+    )]
+    Graph {
+        #[structopt(name = "FILE")]
+        specification: String,
+        #[structopt(
+            long,
+            default_value = "dot",
+            possible_values = &["dot", "json"],
+            help = "The format to write: dot or json."
+        )]
+        format: GraphFormat,
+        #[structopt(
+            long,
+            short,
+            parse(from_os_str),
+            help = "The file to write the graph to, instead of stdout."
+        )]
+        output: Option<PathBuf>,
+    },
+    #[structopt(
+        about = "Audit the ELF files in a bundle.",
+        long_about = "Report, for each ELF file in a built bundle, whether it is position independent, has full RELRO, a non-executable stack and a stack protector, and is free of text relocations and debug info. With --report json, print the findings as JSON."
+    )]
+    Audit {
+        #[structopt(parse(from_os_str), help = "The bundle to audit.")]
+        bundle: PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bundle-gen",
+    long_about = "Generate bundles for the Atari VCS."
+)]
 struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
     #[structopt(name = "FILE")]
-    specification: String,
+    specification: Option<String>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "The directory to write the bundle to.",
+        long_help = "The directory to write the bundle to. Overrides OutputDirectory in the spec; defaults to the current directory."
+    )]
+    output_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "A template for the bundle's file name.",
+        long_help = "A template for the bundle's file name. The tokens {stem}, {name}, {id}, {version} and {type} are replaced by the spec file's stem, and the bundle's name, ID, version and type. Overrides OutputName in the spec; defaults to {stem}_{version}.bundle."
+    )]
+    output_name: Option<String>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "The file to write the build log to.",
+        long_help = "The file to write the build log to. Overrides LogFile in the spec; defaults to <stem>.log in the output directory."
+    )]
+    log_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        possible_values = &["json"],
+        help = "Print a report in the given format instead of the bundle path.",
+        long_help = "Print a report of the build in the given format instead of the bundle path. The report lists the bundle's version, path, size and hash, every file in the bundle and why it was included, how long each stage took, and any warnings."
+    )]
+    report: Option<ReportFormat>,
+    #[structopt(
+        long,
+        help = "List what the bundle would contain, without writing it.",
+        long_help = "Run the build and resolve dependencies, then list each file the bundle would contain, with its source, its size, and why it was included, without writing the bundle itself."
+    )]
+    dry_run: bool,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "The library cache describing the VCS base system.",
+        long_help = "The library cache describing the VCS base system; libraries it lists are never bundled. Overrides the spec's TargetProfile. If not given, the BUNDLE_GEN_BASE_PROFILE environment variable is used, or failing that /usr/local/share/bundle-gen/ld.so.cache.vcs."
+    )]
+    base_profile: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "The policy deciding which libraries may be bundled.",
+        long_help = "The policy deciding which libraries may be bundled: which must never be bundled, which must always be bundled even if the base system has them, and which may be missing. Overrides the spec's TargetProfile. If not given, the BUNDLE_GEN_POLICY environment variable is used, or failing that /usr/local/share/bundle-gen/policy.vcs.yaml if it exists."
+    )]
+    policy: Option<PathBuf>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        parse(from_os_str),
+        help = "Look for target profiles in this directory.",
+        long_help = "Look for target profiles in this directory, before those in BUNDLE_GEN_PROFILE_PATH and /usr/local/share/bundle-gen/profiles. May be given more than once."
+    )]
+    profile_dir: Vec<PathBuf>,
+    #[structopt(
+        long,
+        help = "The target profile to build for, overriding the spec's TargetProfile."
+    )]
+    target_profile: Option<String>,
+}
+
+fn write_listing(
+    libraries: &BaseLibraries,
+    format: ListingFormat,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let listing = libraries.to_format(format)?;
+    match output {
+        Some(path) => fs::write(path, listing)?,
+        None => io::stdout().write_all(&listing)?,
+    }
+    Ok(())
+}
+
+fn generator(opt: &Opt, specification: &str) -> Result<Generator> {
+    let output = OutputOptions {
+        directory: opt.output_dir.clone(),
+        name_template: opt.output_name.clone(),
+        log_file: opt.log_file.clone(),
+    };
+    let wd = std::env::current_dir()?;
+    let mut generator = Generator::from_path(specification)?
+        .search_location(&wd)
+        .working_directory(wd)
+        .output(output)
+        .resolve_options(ResolveOptions {
+            base_profile: opt.base_profile.clone(),
+            policy: opt.policy.clone(),
+            ..Default::default()
+        });
+    if let Some(ref name) = opt.target_profile {
+        generator = generator.target_profile(TargetProfile::find(name, &opt.profile_dir)?);
+    }
+    for dir in opt.profile_dir.iter() {
+        generator = generator.profile_directory(dir);
+    }
+    Ok(generator)
+}
+
+fn why(opt: &Opt, soname: &str, specification: &str) -> Result<()> {
+    let graph = generator(opt, specification)?.dependency_graph()?;
+    let nodes = graph.find(soname);
+    if nodes.is_empty() {
+        return Err(anyhow!("Nothing in the bundle needs {}", soname));
+    }
+    for node in nodes {
+        println!("{} ({}):", node.name, node.kind);
+        let chains = graph.chains_to(&node.name, MAX_WHY_CHAINS + 1);
+        for chain in chains.iter().take(MAX_WHY_CHAINS) {
+            println!("  {}", chain.join(" -> "));
+        }
+        if chains.len() > MAX_WHY_CHAINS {
+            println!("  (more chains not shown)");
+        }
+    }
+    Ok(())
+}
+
+fn graph(
+    opt: &Opt,
+    specification: &str,
+    format: GraphFormat,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let graph = generator(opt, specification)?
+        .dependency_graph()?
+        .export(format)?;
+    match output {
+        Some(path) => fs::write(path, graph)?,
+        None => print!("{}", graph),
+    }
+    Ok(())
+}
+
+fn audit(opt: &Opt, bundle: PathBuf) -> Result<()> {
+    let audits = audit::audit_bundle(bundle)?;
+    match opt.report {
+        Some(ReportFormat::Json) => println!("{}", serde_json::to_string_pretty(&audits)?),
+        None => {
+            for audit in audits {
+                let findings = audit
+                    .findings
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                if findings.is_empty() {
+                    println!("{}: ok", audit.name);
+                } else {
+                    println!("{}: {}", audit.name, findings.join(", "));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build(opt: Opt, specification: String) -> Result<()> {
+    let generator = generator(&opt, &specification)?;
+
+    if opt.dry_run {
+        let files = generator.dry_run()?;
+        match opt.report {
+            Some(ReportFormat::Json) => println!("{}", serde_json::to_string_pretty(&files)?),
+            None => {
+                for file in files {
+                    let mut notes = String::new();
+                    if file.runpath_rewritten {
+                        notes.push_str(", runpath rewritten");
+                    }
+                    if file.stripped {
+                        notes.push_str(", stripped");
+                    }
+                    println!(
+                        "{} <- {} ({} bytes{}): {}",
+                        file.name,
+                        file.source
+                            .as_ref()
+                            .map(|p| p.to_string_lossy().to_string())
+                            .unwrap_or_default(),
+                        file.size.unwrap_or_default(),
+                        notes,
+                        file.reason()
+                    );
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let report = generator.run()?;
+    match opt.report {
+        Some(ReportFormat::Json) => println!("{}", report.to_json()?),
+        None => println!("{}", report.bundle.to_string_lossy()),
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let opt = Opt::from_args();
-    Ok(generate(opt.specification).map(|bundle| println!("{}", bundle.to_string_lossy()))?)
+    let mut opt = Opt::from_args();
+    match opt.command.take() {
+        Some(Command::Profile(ProfileCommand::Create {
+            rootfs,
+            format,
+            output,
+        })) => write_listing(&BaseLibraries::scan_rootfs(rootfs)?, format, output),
+        Some(Command::Profile(ProfileCommand::Convert {
+            input,
+            format,
+            output,
+        })) => write_listing(&BaseLibraries::parse(&fs::read(input)?)?, format, output),
+        Some(Command::Why {
+            soname,
+            specification,
+        }) => why(&opt, &soname, &specification),
+        Some(Command::Graph {
+            specification,
+            format,
+            output,
+        }) => graph(&opt, &specification, format, output),
+        Some(Command::Audit { bundle }) => audit(&opt, bundle),
+        None => {
+            let specification = opt
+                .specification
+                .take()
+                .ok_or_else(|| anyhow!("A specification file is required"))?;
+            build(opt, specification)
+        }
+    }
 }
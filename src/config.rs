@@ -10,6 +10,9 @@ use atari_bundle::BundleType;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::audit::Finding;
+use crate::dlopen::DlopenMode;
+
 #[derive(Debug, Error)]
 pub enum BundleSpecError {
     #[error("IO error opening spec: {0}")]
@@ -44,7 +47,77 @@ pub struct BuildSpec {
     pub libraries: Option<Vec<String>>,
     pub resources: Option<Vec<String>>,
     pub extra_elf_files: Option<Vec<String>>,
+    pub scan_resources: Option<bool>,
+    pub library_search_paths: Option<Vec<String>>,
+    pub scan_dlopen: Option<DlopenMode>,
+    pub framework_plugins: Option<bool>,
+    pub rewrite_runpath: Option<bool>,
+    pub strip: Option<bool>,
+    pub hardening_failures: Option<Vec<Finding>>,
     pub required_modules: Option<Vec<String>>,
+    pub output_directory: Option<String>,
+    pub output_name: Option<String>,
+    pub log_file: Option<String>,
+    pub target_profile: Option<String>,
+}
+
+/// Convert a path written in a spec to use forward slashes, returning
+/// `None` if it is already in canonical form.
+fn normalise_spec_path(path: &str) -> Option<String> {
+    if path.contains('\\') {
+        Some(path.replace('\\', "/"))
+    } else {
+        None
+    }
+}
+
+fn normalise_spec_paths(field: &str, paths: &mut Option<Vec<String>>, warnings: &mut Vec<String>) {
+    if let Some(paths) = paths {
+        for path in paths.iter_mut() {
+            normalise_spec_path_in_place(field, path, warnings);
+        }
+    }
+}
+
+fn normalise_spec_path_in_place(field: &str, path: &mut String, warnings: &mut Vec<String>) {
+    if let Some(canonical) = normalise_spec_path(path) {
+        warnings.push(format!(
+            "{} entry {} uses backslashes as separators; write it as {} instead",
+            field, path, canonical
+        ));
+        *path = canonical;
+    }
+}
+
+impl BuildSpec {
+    /// Rewrite all paths in the build section into platform neutral
+    /// form, so that specs written on Windows hosts behave the same as
+    /// those written elsewhere. Returns a warning for each path that
+    /// needed to be changed.
+    pub fn normalise_paths(&mut self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        normalise_spec_path_in_place("VersionFile", &mut self.version_file, &mut warnings);
+        if let Some(ref mut cmd) = self.build_command {
+            normalise_spec_path_in_place("BuildCommand", cmd, &mut warnings);
+        }
+        normalise_spec_paths("Executables", &mut self.executables, &mut warnings);
+        normalise_spec_paths("Libraries", &mut self.libraries, &mut warnings);
+        normalise_spec_paths("Resources", &mut self.resources, &mut warnings);
+        normalise_spec_paths("ExtraElfFiles", &mut self.extra_elf_files, &mut warnings);
+        normalise_spec_paths(
+            "LibrarySearchPaths",
+            &mut self.library_search_paths,
+            &mut warnings,
+        );
+        normalise_spec_paths("RequiredModules", &mut self.required_modules, &mut warnings);
+        if let Some(ref mut dir) = self.output_directory {
+            normalise_spec_path_in_place("OutputDirectory", dir, &mut warnings);
+        }
+        if let Some(ref mut log) = self.log_file {
+            normalise_spec_path_in_place("LogFile", log, &mut warnings);
+        }
+        warnings
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
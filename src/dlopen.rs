@@ -0,0 +1,200 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use goblin::elf::Elf;
+use ldcache_rs::Cache;
+use log::trace;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::baselibs::BaseLibraries;
+use crate::graph::{DependencyGraph, GraphNode, NodeKind};
+use crate::policy::Policy;
+
+#[derive(Debug, Error)]
+pub enum DlopenError {
+    #[error("IO error while scanning {} for dlopen targets: {1}", .0.to_string_lossy())]
+    Io(PathBuf, std::io::Error),
+    #[error("unable to read the build library cache: {0}")]
+    Cache(#[from] ldcache_rs::CacheError),
+}
+
+/// What to do with libraries an ELF file probably loads with `dlopen`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DlopenMode {
+    /// Warn about each one, so it can be listed in the spec
+    Warn,
+    /// Add each one to the bundle, along with its dependencies
+    Include,
+}
+
+/// A library an ELF file names, but doesn't list in DT_NEEDED, which
+/// the build image has
+#[derive(Clone, Debug)]
+pub struct DlopenCandidate {
+    pub soname: String,
+    /// Where the build image has the library
+    pub path: PathBuf,
+    /// The graph node for the file naming the library
+    pub named_by: String,
+    /// Where the file naming the library is
+    pub named_by_path: PathBuf,
+}
+
+/// Whether a string looks like a soname, like `libasound.so.2`
+fn is_soname(s: &str) -> bool {
+    let stem = match s.strip_prefix("lib") {
+        Some(stem) => stem,
+        None => return false,
+    };
+    let (name, version) = match stem.find(".so") {
+        Some(i) => (&stem[..i], &stem[i + 3..]),
+        None => return false,
+    };
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+.".contains(c))
+        && (version.is_empty()
+            || (version.starts_with('.')
+                && version.len() > 1
+                && version.chars().all(|c| c.is_ascii_digit() || c == '.')))
+}
+
+/// Find the strings in an ELF file's `.rodata` that look like sonames
+pub fn sonames_in_rodata(buf: &[u8]) -> Vec<String> {
+    let elf = match Elf::parse(buf) {
+        Ok(elf) => elf,
+        Err(_) => return Vec::new(),
+    };
+    let mut sonames = Vec::new();
+    for sh in elf.section_headers.iter() {
+        if elf.shdr_strtab.get_at(sh.sh_name) != Some(".rodata") {
+            continue;
+        }
+        let data = match sh.file_range().and_then(|range| buf.get(range)) {
+            Some(data) => data,
+            None => continue,
+        };
+        for s in data.split(|b| *b == 0) {
+            if let Ok(s) = std::str::from_utf8(s) {
+                if is_soname(s) && !sonames.iter().any(|n| n == s) {
+                    sonames.push(s.to_string());
+                }
+            }
+        }
+    }
+    sonames
+}
+
+/// Scan the `.rodata` of a file in a dependency graph for sonames the
+/// graph doesn't already account for, adding them to the candidates
+fn scan_node(
+    node: &GraphNode,
+    graph: &DependencyGraph,
+    base: &BaseLibraries,
+    policy: &Policy,
+    build_deps: &Cache,
+    candidates: &mut BTreeMap<String, DlopenCandidate>,
+) -> Result<(), DlopenError> {
+    let location = match node.path {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+    trace!("Scanning {} for dlopen targets", location.to_string_lossy());
+    let buf = fs::read(location).map_err(|e| DlopenError::Io(location.clone(), e))?;
+    for soname in sonames_in_rodata(&buf) {
+        if candidates.contains_key(&soname)
+            || !graph.find(&soname).is_empty()
+            || base.contains(&soname)
+            || policy.never_bundle(&soname).is_some()
+        {
+            continue;
+        }
+        if let Some(path) = build_deps.get_path(&soname) {
+            let path: &Path = path.as_ref();
+            candidates.insert(
+                soname.clone(),
+                DlopenCandidate {
+                    soname,
+                    path: path.to_path_buf(),
+                    named_by: node.name.clone(),
+                    named_by_path: location.clone(),
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Scan the `.rodata` of every own and bundled file in a dependency
+/// graph for sonames it doesn't already account for, which are likely
+/// `dlopen` targets. Libraries the base system provides, that policy
+/// keeps out of bundles, or that the build image doesn't have, are
+/// skipped.
+pub fn find_candidates(
+    graph: &DependencyGraph,
+    base: &BaseLibraries,
+    policy: &Policy,
+) -> Result<Vec<DlopenCandidate>, DlopenError> {
+    let build_deps = Cache::new()?;
+    let mut candidates = BTreeMap::new();
+    for node in graph
+        .nodes()
+        .filter(|n| matches!(n.kind, NodeKind::Own | NodeKind::Bundled))
+    {
+        scan_node(node, graph, base, policy, &build_deps, &mut candidates)?;
+    }
+    Ok(candidates.into_values().collect())
+}
+
+/// Like [`find_candidates`], but only scanning a single file
+pub fn find_candidates_in(
+    node: &GraphNode,
+    graph: &DependencyGraph,
+    base: &BaseLibraries,
+    policy: &Policy,
+) -> Result<Vec<DlopenCandidate>, DlopenError> {
+    let mut candidates = BTreeMap::new();
+    scan_node(node, graph, base, policy, &Cache::new()?, &mut candidates)?;
+    Ok(candidates.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_sonames() {
+        assert!(is_soname("libasound.so.2"));
+        assert!(is_soname("libvulkan.so.1"));
+        assert!(is_soname("libGL.so"));
+        assert!(is_soname("libstdc++.so.6"));
+        assert!(is_soname("libSDL2-2.0.so.0"));
+        assert!(is_soname("libfoo_bar.so.1.2.3"));
+    }
+
+    #[test]
+    fn rejects_other_strings() {
+        // Not a library name
+        assert!(!is_soname("asound.so.2"));
+        assert!(!is_soname("lib"));
+        assert!(!is_soname("lib.so.1"));
+        assert!(!is_soname("libfoo"));
+        // Versions must be dot separated numbers
+        assert!(!is_soname("libfoo.so."));
+        assert!(!is_soname("libfoo.so.1a"));
+        assert!(!is_soname("libfoo.sox"));
+        assert!(!is_soname("libfoo.so.%d"));
+        // Paths and messages aren't sonames
+        assert!(!is_soname("libfoo/bar.so.1"));
+        assert!(!is_soname("libfoo.so.1 not found"));
+        assert!(!is_soname("libfoo%s.so"));
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use log::trace;
+use thiserror::Error;
+
+use crate::baselibs::BaseLibraries;
+use crate::dlopen::{self, DlopenError};
+use crate::graph::{DependencyGraph, GraphNode, NodeKind};
+use crate::policy::Policy;
+use crate::FileEntry;
+
+#[derive(Debug, Error)]
+pub enum FrameworkError {
+    #[error("unable to find the backends SDL loads: {0}")]
+    Dlopen(#[from] DlopenError),
+}
+
+/// A framework that loads plugins the dependency graph can't see
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framework {
+    Qt5,
+    Qt6,
+    GStreamer,
+    Sdl2,
+}
+
+impl fmt::Display for Framework {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Framework::Qt5 => "Qt 5",
+            Framework::Qt6 => "Qt 6",
+            Framework::GStreamer => "GStreamer",
+            Framework::Sdl2 => "SDL 2",
+        })
+    }
+}
+
+/// Every framework we know how to collect plugins for, with the soname
+/// that shows a bundle uses it
+const FRAMEWORKS: &[(Framework, &str)] = &[
+    (Framework::Qt5, "libQt5Core.so.5"),
+    (Framework::Qt6, "libQt6Core.so.6"),
+    (Framework::GStreamer, "libgstreamer-1.0.so.0"),
+    (Framework::Sdl2, "libSDL2-2.0.so.0"),
+];
+
+/// The Qt plugin directories needed to start up and display images
+const QT_PLUGIN_DIRS: &[&str] = &[
+    "platforms",
+    "platforminputcontexts",
+    "xcbglintegrations",
+    "egldeviceintegrations",
+    "imageformats",
+    "iconengines",
+];
+
+/// The plugins a framework needs in the bundle, and how to find them
+/// once it's installed
+#[derive(Clone, Debug)]
+pub struct PluginSet {
+    pub framework: Framework,
+    /// The framework library the plugins are for
+    pub library: PathBuf,
+    /// Directories to copy in full, and where they go in the bundle
+    pub directories: Vec<(PathBuf, String)>,
+    /// Single files to copy
+    pub files: Vec<FileEntry>,
+    /// Environment variables for the generated launch scripts, with
+    /// paths relative to the bundle's root
+    pub environment: Vec<(String, String)>,
+}
+
+impl PluginSet {
+    fn new(framework: Framework, library: &Path) -> Self {
+        Self {
+            framework,
+            library: library.to_path_buf(),
+            directories: Vec::new(),
+            files: Vec::new(),
+            environment: Vec::new(),
+        }
+    }
+}
+
+fn first_dir(candidates: &[PathBuf]) -> Option<&PathBuf> {
+    candidates.iter().find(|dir| dir.is_dir())
+}
+
+fn collect_qt(set: &mut PluginSet, libdir: &Path, major: &str) {
+    let qt = format!("qt{}", major);
+    let plugins = match first_dir(&[
+        libdir.join(&qt).join("plugins"),
+        libdir.join("..").join("plugins"),
+    ]) {
+        Some(dir) => dir.clone(),
+        None => return,
+    };
+    let dest = format!("plugins/{}", qt);
+    for dir in QT_PLUGIN_DIRS {
+        let path = plugins.join(dir);
+        if path.is_dir() {
+            set.directories.push((path, format!("{}/{}", dest, dir)));
+        }
+    }
+    set.environment.push(("QT_PLUGIN_PATH".to_string(), dest));
+}
+
+fn collect_gstreamer(set: &mut PluginSet, libdir: &Path) {
+    if let Some(plugins) = first_dir(&[libdir.join("gstreamer-1.0")]) {
+        let dest = "plugins/gstreamer-1.0".to_string();
+        set.directories.push((plugins.clone(), dest.clone()));
+        set.environment
+            .push(("GST_PLUGIN_SYSTEM_PATH_1_0".to_string(), dest));
+    }
+    let scanner = [
+        libdir.join("gstreamer1.0/gstreamer-1.0/gst-plugin-scanner"),
+        libdir.join("../libexec/gstreamer-1.0/gst-plugin-scanner"),
+    ];
+    if let Some(scanner) = scanner.iter().find(|p| p.is_file()) {
+        let dest = "libexec/gst-plugin-scanner".to_string();
+        set.files.push(FileEntry {
+            location: scanner.clone(),
+            name: dest.clone(),
+        });
+        set.environment
+            .push(("GST_PLUGIN_SCANNER_1_0".to_string(), dest));
+    }
+}
+
+/// SDL has no plugin directories, but loads its audio and video
+/// backends with `dlopen`, by soname
+fn collect_sdl(
+    set: &mut PluginSet,
+    node: &GraphNode,
+    graph: &DependencyGraph,
+    base: &BaseLibraries,
+    policy: &Policy,
+) -> Result<(), FrameworkError> {
+    for candidate in dlopen::find_candidates_in(node, graph, base, policy)? {
+        set.files.push(FileEntry {
+            location: candidate.path,
+            name: format!("lib/{}", candidate.soname),
+        });
+    }
+    Ok(())
+}
+
+/// Find the frameworks a dependency graph's own or bundled files use,
+/// and the plugins each needs. Frameworks the base system provides
+/// already have their plugins on the console, so are skipped.
+pub fn collect_plugins(
+    graph: &DependencyGraph,
+    base: &BaseLibraries,
+    policy: &Policy,
+) -> Result<Vec<PluginSet>, FrameworkError> {
+    let mut sets = Vec::new();
+    for (framework, soname) in FRAMEWORKS {
+        let node = graph
+            .find(soname)
+            .into_iter()
+            .find(|n| matches!(n.kind, NodeKind::Own | NodeKind::Bundled));
+        let (node, library) = match node.and_then(|n| n.path.as_ref().map(|p| (n, p))) {
+            Some(found) => found,
+            None => continue,
+        };
+        trace!("Collecting {} plugins for {}", framework, node.name);
+        let libdir = library.parent().unwrap_or_else(|| Path::new("/"));
+        let mut set = PluginSet::new(*framework, library);
+        match framework {
+            Framework::Qt5 => collect_qt(&mut set, libdir, "5"),
+            Framework::Qt6 => collect_qt(&mut set, libdir, "6"),
+            Framework::GStreamer => collect_gstreamer(&mut set, libdir),
+            Framework::Sdl2 => collect_sdl(&mut set, node, graph, base, policy)?,
+        }
+        sets.push(set);
+    }
+    Ok(sets)
+}
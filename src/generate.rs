@@ -9,14 +9,27 @@ use std::io::{Seek, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Instant;
 
 use atari_bundle::{BundleConfig, BundleError};
 use log::trace;
+use serde::Serialize;
+use tempdir::TempDir;
 use thiserror::Error;
 use zip::ZipWriter;
 
+use crate::abi::{AbiError, TargetAbi};
 use crate::config::{BuildSpec, BundleSpec, BundleSpecError};
-use crate::ldcache::{self, LdError};
+use crate::dlopen::{self, DlopenError, DlopenMode};
+use crate::frameworks::{self, FrameworkError, PluginSet};
+use crate::graph::{DependencyGraph, NodeKind};
+use crate::ldcache::{self, Dependency, LdError, ResolveOptions};
+use crate::profile::{ProfileError, TargetProfile};
+use crate::report::{self, BuildReport, BundledFile, FileOrigin, StageTiming};
+use crate::runpath::{self, RunpathError};
+use crate::strip::{self, DebugFile, StripError};
+use crate::validate::{self, EntryNameError};
+use crate::verify::{self, VerifyError};
 use crate::FileEntry;
 
 #[derive(Debug, Error)]
@@ -55,12 +68,171 @@ pub enum BuildError {
     BadCommand(String),
     #[error("the bundle entry {0} was specified multiple times")]
     DuplicateZipFileEntry(String),
+    #[error("invalid bundle entry name: {0}")]
+    EntryName(#[from] EntryNameError),
     #[error("error writing to log file: {0}")]
-    LogError(std::io::Error)
+    LogError(std::io::Error),
+    #[error("unable to load target profile: {0}")]
+    Profile(#[from] ProfileError),
+    #[error("generated bundle failed verification: {0}")]
+    Verify(#[from] VerifyError),
+    #[error("bad output name template {0}: {1}")]
+    BadTemplate(String, String),
+    #[error("unable to rewrite runpath: {0}")]
+    Runpath(#[from] RunpathError),
+    #[error("files don't match the target architecture: {0}")]
+    Abi(#[from] AbiError),
+    #[error("unable to strip debug info: {0}")]
+    Strip(#[from] StripError),
+    #[error("unable to scan for dlopen targets: {0}")]
+    Dlopen(#[from] DlopenError),
+    #[error("unable to collect framework plugins: {0}")]
+    Framework(#[from] FrameworkError),
 }
 
 type BuildResult<T> = Result<T, BuildError>;
 
+/// The stages of a build, in the order they happen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    InstallingPackages,
+    RunningModules,
+    Building,
+    CollectingFiles,
+    ResolvingDependencies,
+    WritingBundle,
+    Verifying,
+    Finished,
+}
+
+/// A callback notified as a build moves between stages
+pub type ProgressCallback = Box<dyn FnMut(Stage) + Send>;
+
+/// The build log, which also keeps hold of any warnings issued, so they
+/// can be reported at the end of the build.
+struct BuildLog {
+    sink: Box<dyn Write>,
+    warnings: Vec<String>,
+}
+
+impl BuildLog {
+    fn new(sink: Box<dyn Write>) -> Self {
+        Self {
+            sink,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn warn<S: Into<String>>(&mut self, warning: S) -> BuildResult<()> {
+        let warning = warning.into();
+        writeln!(self.sink, "WARNING: {}", warning).map_err(BuildError::LogError)?;
+        self.warnings.push(warning);
+        Ok(())
+    }
+}
+
+impl Write for BuildLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sink.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// The default template for naming generated bundles
+pub const DEFAULT_OUTPUT_NAME: &str = "{stem}_{version}.bundle";
+
+/// Where the products of a build are written. Anything left unset falls
+/// back to the spec, and then to the defaults.
+#[derive(Clone, Debug, Default)]
+pub struct OutputOptions {
+    /// The directory the bundle is written to; defaults to the current directory
+    pub directory: Option<PathBuf>,
+    /// A template for the bundle's file name, which may contain the tokens
+    /// `{stem}`, `{name}`, `{id}`, `{version}` and `{type}`
+    pub name_template: Option<String>,
+    /// The build log; defaults to `<stem>.log` in the output directory
+    pub log_file: Option<PathBuf>,
+}
+
+impl OutputOptions {
+    /// Fill in anything not given explicitly from the spec, or failing
+    /// that, from the defaults.
+    fn resolve(&self, spec: &BuildSpec, stem: &str, base: &Path) -> OutputOptions {
+        let directory = base.join(
+            self.directory
+                .clone()
+                .or_else(|| spec.output_directory.as_ref().map(PathBuf::from))
+                .unwrap_or_default(),
+        );
+        let name_template = self
+            .name_template
+            .clone()
+            .or_else(|| spec.output_name.clone())
+            .unwrap_or_else(|| DEFAULT_OUTPUT_NAME.to_string());
+        let log_file = self
+            .log_file
+            .clone()
+            .or_else(|| spec.log_file.as_ref().map(PathBuf::from))
+            .map(|f| base.join(f))
+            .unwrap_or_else(|| directory.join(format!("{}.log", stem)));
+        OutputOptions {
+            directory: Some(directory),
+            name_template: Some(name_template),
+            log_file: Some(log_file),
+        }
+    }
+}
+
+fn expand_output_name(template: &str, tokens: &[(&str, &str)]) -> BuildResult<String> {
+    let mut res = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            BuildError::BadTemplate(template.to_string(), "unterminated token".to_string())
+        })?;
+        let token = &rest[start + 1..start + end];
+        let (_, value) = tokens.iter().find(|(t, _)| *t == token).ok_or_else(|| {
+            BuildError::BadTemplate(template.to_string(), format!("unknown token {{{}}}", token))
+        })?;
+        // Values come from the spec, so keep them from adding directories
+        res.push_str(&value.replace('/', "_"));
+        rest = &rest[start + end + 1..];
+    }
+    res.push_str(rest);
+    if res.is_empty() || res.contains('/') {
+        return Err(BuildError::BadTemplate(
+            template.to_string(),
+            "must expand to a file name".to_string(),
+        ));
+    }
+    Ok(res)
+}
+
+/// Where a bundle is written until it has been verified, like
+/// `game_1.0.bundle.partial` for `game_1.0.bundle`
+fn partial_bundle_path(bundle: &Path) -> PathBuf {
+    let mut name = bundle.to_path_buf().into_os_string();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// The debug archive written alongside a bundle, like
+/// `game_1.0.debug.zip` for `game_1.0.bundle`
+fn debug_archive_path(bundle: &Path) -> PathBuf {
+    let mut name = match bundle.extension() {
+        Some(ext) if ext == "bundle" => bundle.with_extension(""),
+        _ => bundle.to_path_buf(),
+    }
+    .into_os_string();
+    name.push(".debug.zip");
+    PathBuf::from(name)
+}
+
 struct PathContext {
     locations: Vec<PathBuf>,
 }
@@ -84,11 +256,15 @@ impl PathContext {
     }
 }
 
-fn process_dir<P, Q, W>(path: P, entry_name: Q, files: &mut Vec<FileEntry>, log: &mut W) -> BuildResult<()>
+fn process_dir<P, Q>(
+    path: P,
+    entry_name: Q,
+    files: &mut Vec<FileEntry>,
+    log: &mut BuildLog,
+) -> BuildResult<()>
 where
     P: AsRef<Path>,
     Q: Into<String>,
-    W: Write
 {
     let e = entry_name.into();
     trace!("processing dir {:?} under entry {}", path.as_ref(), e);
@@ -114,22 +290,23 @@ where
                             entry.path(),
                             Path::new(&e).join(relpath).to_string_lossy().to_string(),
                             files,
-                            log
+                            log,
                         )?
                     } else {
-                        writeln!(log, "WARNING: Skipped entry {}: only files and directories are supported.",
-                                 entry.path().to_string_lossy())
-                            .map_err(BuildError::LogError)?;
+                        log.warn(format!(
+                            "Skipped entry {}: only files and directories are supported.",
+                            entry.path().to_string_lossy()
+                        ))?;
                     }
                 } else {
-                    writeln!(log, "WARNING: Skipped entry {}: not a valid path.",
-                             entry.path().to_string_lossy())
-                        .map_err(BuildError::LogError)?;
+                    log.warn(format!(
+                        "Skipped entry {}: not a valid path.",
+                        entry.path().to_string_lossy()
+                    ))?;
                 }
             }
             Err(e) => {
-                writeln!(log, "WARNING: Skipped entry: {}", e)
-                    .map_err(BuildError::LogError)?;
+                log.warn(format!("Skipped entry: {}", e))?;
             }
         }
     }
@@ -143,13 +320,16 @@ where
     let mut entry_map = BTreeMap::new();
 
     for file in files {
-        if let Some(old_location) = entry_map.insert(file.name.clone(), file.location.clone()) {
+        let name = validate::normalise_entry_name(&file.name)?;
+        if let Some(old_location) = entry_map.insert(name.clone(), file.location.clone()) {
             if old_location != file.location {
-                return Err(BuildError::DuplicateZipFileEntry(file.name.clone()));
+                return Err(BuildError::DuplicateZipFileEntry(name));
             }
         }
     }
 
+    validate::check_case_collisions(entry_map.keys())?;
+
     let mut last_path: Option<String> = None;
     for kv in entry_map {
         let (name, location) = &kv;
@@ -228,17 +408,16 @@ fn run_command<W: Write>(cmd: &mut Command, mut log: W) -> BuildResult<()> {
     Ok(())
 }
 
-fn process_file_items<P, Q, W>(
+fn process_file_items<P, Q>(
     items: &[P],
     base_path: Q,
     pc: &PathContext,
     entries: &mut Vec<FileEntry>,
-    log: &mut W
+    log: &mut BuildLog,
 ) -> BuildResult<()>
 where
     P: AsRef<Path>,
     Q: Into<String>,
-    W: Write
 {
     let s = base_path.into();
     for item in items {
@@ -261,69 +440,249 @@ where
                 };
                 process_dir(path, filename.to_string_lossy().to_string(), entries, log)?;
             } else {
-                writeln!(log, "WARNING: skipped entry {}: only files and directories are supported.",
-                         filename.to_string_lossy())
-                    .map_err(BuildError::LogError)?;
+                log.warn(format!(
+                    "skipped entry {}: only files and directories are supported.",
+                    filename.to_string_lossy()
+                ))?;
             }
         } else {
-            writeln!(log, "WARNING: skipped entry {}: not a valid path.", item.as_ref().to_string_lossy())
-                .map_err(BuildError::LogError)?;
+            log.warn(format!(
+                "skipped entry {}: not a valid path.",
+                item.as_ref().to_string_lossy()
+            ))?;
         }
     }
 
     Ok(())
 }
 
-fn build_phase(
-    b: &BuildSpec,
-    stem: &str,
-    pc: &PathContext,
-) -> BuildResult<(PathBuf, ZipWriter<File>, String)> {
-    let mut log_file = PathBuf::from(stem);
-    log_file.set_extension("log");
-    let mut build_log = File::create(&log_file).map_err(|e| BuildError::IO(log_file, e))?;
+/// Everything a single build carries between its phases
+struct BuildContext<'a> {
+    cfg: &'a BundleSpec,
+    stem: &'a str,
+    pc: &'a PathContext,
+    output: &'a OutputOptions,
+    resolve: &'a ResolveOptions,
+    working_directory: &'a Path,
+    build_log: &'a mut BuildLog,
+    progress: Option<&'a mut ProgressCallback>,
+    current_stage: Option<(Stage, Instant)>,
+    timings: Vec<StageTiming>,
+    files: BTreeMap<String, BundledFile>,
+    /// Debug info stripped from the bundle's files, written out once the
+    /// bundle is verified
+    debug_files: Vec<DebugFile>,
+    debug_archive: Option<PathBuf>,
+    /// Where the bundle is written until it has been verified
+    partial_bundle: Option<PathBuf>,
+    /// Where patched and stripped copies of files are written
+    scratch: Option<TempDir>,
+    /// Environment variables the launch scripts set, with paths
+    /// relative to the bundle's root
+    environment: Vec<(String, String)>,
+}
+
+impl<'a> BuildContext<'a> {
+    fn stage(&mut self, stage: Stage) {
+        trace!("Entering stage {:?}", stage);
+        if let Some((previous, start)) = self.current_stage.take() {
+            self.timings.push(StageTiming {
+                stage: previous,
+                seconds: start.elapsed().as_secs_f64(),
+            });
+        }
+        if stage != Stage::Finished {
+            self.current_stage = Some((stage, Instant::now()));
+        }
+        if let Some(ref mut progress) = self.progress {
+            progress(stage);
+        }
+    }
+
+    /// Note a file destined for the bundle, for the build report
+    fn record(
+        &mut self,
+        entry: &FileEntry,
+        origin: FileOrigin,
+        needed_by: Option<&Path>,
+    ) -> BuildResult<()> {
+        let name = validate::normalise_entry_name(&entry.name)?;
+        let size = fs::metadata(&entry.location)
+            .map_err(|e| BuildError::IO(entry.location.clone(), e))?
+            .len();
+        self.files.insert(
+            name.clone(),
+            BundledFile {
+                name,
+                source: Some(entry.location.clone()),
+                size: Some(size),
+                origin,
+                needed_by: needed_by.map(Path::to_path_buf),
+                runpath_rewritten: false,
+                stripped: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Note that a file will be replaced in the bundle by a rewritten or
+    /// stripped copy, so the build report gives the copy's size
+    fn record_processed(
+        &mut self,
+        entry: &FileEntry,
+        copy: &FileEntry,
+        update: fn(&mut BundledFile),
+    ) -> BuildResult<()> {
+        let name = validate::normalise_entry_name(&entry.name)?;
+        let size = fs::metadata(&copy.location)
+            .map_err(|e| BuildError::IO(copy.location.clone(), e))?
+            .len();
+        if let Some(file) = self.files.get_mut(&name) {
+            file.size = Some(size);
+            update(file);
+        }
+        Ok(())
+    }
+
+    fn record_collected(&mut self, collected: &Collected) -> BuildResult<()> {
+        for entry in collected.executables.iter() {
+            self.record(entry, FileOrigin::Executable, None)?;
+        }
+        for entry in collected.libraries.iter() {
+            self.record(entry, FileOrigin::Library, None)?;
+        }
+        for entry in collected
+            .resources
+            .iter()
+            .chain(collected.resource_elves.iter())
+        {
+            self.record(entry, FileOrigin::Resource, None)?;
+        }
+        for dep in collected.graph.dependencies() {
+            self.record(&dep.entry, FileOrigin::Dependency, Some(&dep.needed_by))?;
+        }
+        for dep in collected.dlopened.iter() {
+            self.record(&dep.entry, FileOrigin::Dlopen, Some(&dep.needed_by))?;
+        }
+        for set in collected.plugins.iter() {
+            for entry in set.files.iter() {
+                self.record(entry, FileOrigin::Plugin, Some(&set.library))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record_generated(&mut self, name: &str) {
+        self.files.insert(
+            name.to_string(),
+            BundledFile {
+                name: name.to_string(),
+                source: None,
+                size: None,
+                origin: FileOrigin::Generated,
+                needed_by: None,
+                runpath_rewritten: false,
+                stripped: false,
+            },
+        );
+    }
+
+    fn command<S: AsRef<std::ffi::OsStr>>(&self, program: S) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.current_dir(self.working_directory);
+        cmd
+    }
+}
+
+/// The files found on disk that will make up a bundle
+struct Collected {
+    executables: Vec<FileEntry>,
+    libraries: Vec<FileEntry>,
+    resources: Vec<FileEntry>,
+    /// ELF shared objects found among the resources, whose dependencies
+    /// are resolved
+    resource_elves: Vec<FileEntry>,
+    /// Libraries included because something probably loads them with
+    /// `dlopen`
+    dlopened: Vec<Dependency>,
+    /// Plugins for the frameworks the bundle uses
+    plugins: Vec<PluginSet>,
+    graph: DependencyGraph,
+}
+
+/// Run the build, then find the files it produced and their dependencies.
+fn scan_phase(ctx: &mut BuildContext) -> BuildResult<Collected> {
+    let b = &ctx.cfg.build;
+    let pc = ctx.pc;
 
     if let Some(ref deps) = b.required_packages {
+        ctx.stage(Stage::InstallingPackages);
         run_command(
-            Command::new("apt-get")
+            ctx.command("apt-get")
                 .arg("install")
                 .arg("-y")
                 .env("DEBIAN_FRONTEND", "noninteractive")
                 .args(deps),
-            &mut build_log,
+            &mut ctx.build_log,
         )?;
     }
 
     if let Some(ref modules) = b.required_modules {
+        ctx.stage(Stage::RunningModules);
         for module in modules {
             // Install/build the module
             let path = pc.find_path(module)?;
             trace!("Discovered module file at {:?}", path);
-            run_command(&mut Command::new(&path), &mut build_log)?;
+            run_command(&mut ctx.command(&path), &mut ctx.build_log)?;
         }
 
-        run_command(&mut Command::new("ldconfig"), &mut build_log)?;
+        run_command(&mut ctx.command("ldconfig"), &mut ctx.build_log)?;
     }
 
     // Do the build itself
     if let Some(ref cmd) = b.build_command {
+        ctx.stage(Stage::Building);
         let path = pc.find_path(cmd)?;
-        run_command(&mut Command::new(&path), &mut build_log)?;
+        run_command(&mut ctx.command(&path), &mut ctx.build_log)?;
     }
 
+    ctx.stage(Stage::CollectingFiles);
+
     let mut executables_on_disk = Vec::new();
     if let Some(ref executables) = b.executables {
-        process_file_items(executables, "bin", pc, &mut executables_on_disk, &mut build_log)?;
+        process_file_items(
+            executables,
+            "bin",
+            pc,
+            &mut executables_on_disk,
+            ctx.build_log,
+        )?;
     }
 
     let mut libraries_on_disk = Vec::new();
     if let Some(ref libraries) = b.libraries {
-        process_file_items(libraries, "lib", pc, &mut libraries_on_disk, &mut build_log)?;
+        process_file_items(libraries, "lib", pc, &mut libraries_on_disk, ctx.build_log)?;
     }
 
     let mut resources_on_disk = Vec::new();
     if let Some(ref resources) = b.resources {
-        process_file_items(resources, "res", pc, &mut resources_on_disk, &mut build_log)?;
+        process_file_items(resources, "res", pc, &mut resources_on_disk, ctx.build_log)?;
+    }
+
+    // Engines may keep native extensions among their data, so when asked,
+    // pick out the shared objects to resolve their dependencies too
+    let mut resource_elves = Vec::new();
+    if b.scan_resources.unwrap_or(false) {
+        let mut rest = Vec::new();
+        for entry in resources_on_disk {
+            if ldcache::is_shared_object(&entry.location)? {
+                trace!("Found shared object {} in resources", entry.name);
+                resource_elves.push(entry);
+            } else {
+                rest.push(entry);
+            }
+        }
+        resources_on_disk = rest;
     }
 
     // These are elf files that we believe hold dependencies we'd otherwise miss,
@@ -331,7 +690,7 @@ fn build_phase(
     // still be installed by listing them under resources, for example).
     let mut extra_elf_on_disk = Vec::new();
     if let Some(ref files) = b.extra_elf_files {
-        process_file_items(files, "_unused", pc, &mut extra_elf_on_disk, &mut build_log)?;
+        process_file_items(files, "extra", pc, &mut extra_elf_on_disk, ctx.build_log)?;
     }
 
     // elf files that can't provide dependencies, like executables and plugins
@@ -339,6 +698,7 @@ fn build_phase(
         .iter()
         .chain(extra_elf_on_disk.iter())
         .chain(libraries_on_disk.iter())
+        .chain(resource_elves.iter())
         .cloned()
         .collect::<Vec<_>>();
 
@@ -347,30 +707,293 @@ fn build_phase(
         trace!(" - {}", elf.location.to_string_lossy());
     }
 
-    let dependencies_on_disk = ldcache::resolve_deps(elves)?;
+    ctx.stage(Stage::ResolvingDependencies);
+    let mut resolve = ctx.resolve.clone();
+    if let Some(ref dirs) = b.library_search_paths {
+        for dir in dirs {
+            resolve.library_search_paths.push(pc.find_path(dir)?);
+        }
+    }
+    let mut graph = ldcache::dependency_graph(elves.clone(), &resolve)?;
+
+    // Frameworks load their plugins from their own directories, so
+    // collect those, then find what the plugins need
+    let mut plugins = Vec::new();
+    let mut elves = elves;
+    if b.framework_plugins.unwrap_or(false) {
+        let base = resolve.load_base_deps()?;
+        let policy = resolve.load_policy()?;
+        for mut set in frameworks::collect_plugins(&graph, &base, &policy)? {
+            writeln!(
+                ctx.build_log,
+                "Collecting {} plugins for {}",
+                set.framework,
+                set.library.to_string_lossy()
+            )
+            .map_err(BuildError::LogError)?;
+            for (dir, name) in set.directories.iter() {
+                process_dir(dir, name.as_str(), &mut set.files, ctx.build_log)?;
+            }
+            elves.extend(set.files.iter().cloned());
+            plugins.push(set);
+        }
+        if !plugins.is_empty() {
+            graph = ldcache::dependency_graph(elves.clone(), &resolve)?;
+        }
+    }
+
+    // Libraries loaded with dlopen aren't in DT_NEEDED, so look for
+    // their names instead. Anything included may name more.
+    let mut dlopened = Vec::new();
+    if let Some(mode) = b.scan_dlopen {
+        let base = resolve.load_base_deps()?;
+        let policy = resolve.load_policy()?;
+        loop {
+            let candidates = dlopen::find_candidates(&graph, &base, &policy)?;
+            if candidates.is_empty() {
+                break;
+            }
+            for c in candidates.iter() {
+                let path = c.path.to_string_lossy();
+                match mode {
+                    DlopenMode::Warn => ctx.build_log.warn(format!(
+                        "{} names {}, which the build image has at {}; list it under Libraries if it is loaded with dlopen",
+                        c.named_by, c.soname, path
+                    ))?,
+                    DlopenMode::Include => writeln!(
+                        ctx.build_log,
+                        "Including {} from {}, since {} names it",
+                        c.soname, path, c.named_by
+                    )
+                    .map_err(BuildError::LogError)?,
+                }
+            }
+            if mode == DlopenMode::Warn {
+                break;
+            }
+            dlopened.extend(candidates.into_iter().map(|c| {
+                Dependency {
+                    entry: FileEntry {
+                        name: Path::new("lib")
+                            .join(&c.soname)
+                            .to_string_lossy()
+                            .to_string(),
+                        location: c.path,
+                    },
+                    soname: c.soname,
+                    needed_by: c.named_by_path,
+                }
+            }));
+            graph = ldcache::dependency_graph(
+                elves
+                    .iter()
+                    .chain(dlopened.iter().map(|d| &d.entry))
+                    .cloned()
+                    .collect(),
+                &resolve,
+            )?;
+        }
+    }
+
+    Ok(Collected {
+        executables: executables_on_disk,
+        libraries: libraries_on_disk,
+        resources: resources_on_disk,
+        resource_elves,
+        dlopened,
+        plugins,
+        graph,
+    })
+}
+
+/// Write the shortest chain of DT_NEEDED entries from each listed file
+/// that led to each library being bundled, excluded by policy or found
+/// to be missing, to the build log.
+fn log_dependency_chains(graph: &DependencyGraph, log: &mut BuildLog) -> BuildResult<()> {
+    let chains = graph.shortest_chains();
+    writeln!(log, "Dependencies:").map_err(BuildError::LogError)?;
+    for node in graph.nodes().filter(|n| {
+        matches!(
+            n.kind,
+            NodeKind::Bundled | NodeKind::Excluded | NodeKind::Missing
+        )
+    }) {
+        writeln!(log, "  {} ({}):", node.name, node.kind).map_err(BuildError::LogError)?;
+        for chain in chains.get(node.name.as_str()).into_iter().flatten() {
+            writeln!(log, "    {}", chain.join(" -> ")).map_err(BuildError::LogError)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the build, then find everything that should go into the bundle.
+fn collect_phase(ctx: &mut BuildContext) -> BuildResult<Collected> {
+    let collected = scan_phase(ctx)?;
+    log_dependency_chains(&collected.graph, ctx.build_log)?;
+    for warning in collected.graph.warnings() {
+        ctx.build_log.warn(warning.clone())?;
+    }
+    collected.graph.check()?;
+    TargetAbi::for_profile(ctx.resolve.target_profile.as_ref())?.check_all(
+        collected
+            .executables
+            .iter()
+            .chain(collected.libraries.iter())
+            .chain(collected.resource_elves.iter())
+            .chain(collected.dlopened.iter().map(|d| &d.entry))
+            .chain(collected.plugins.iter().flat_map(|s| s.files.iter()))
+            .chain(collected.graph.dependencies().iter().map(|d| &d.entry)),
+    )?;
+    ctx.record_collected(&collected)?;
+    Ok(collected)
+}
+
+/// The files to write into a bundle, once runpaths have been rewritten
+/// and debug info stripped
+struct Prepared {
+    elves: Vec<FileEntry>,
+    resources: Vec<FileEntry>,
+}
+
+/// Find everything that should go into the bundle, then rewrite the
+/// runpaths of its ELF files and strip them, if asked to.
+fn prepare_phase(ctx: &mut BuildContext) -> BuildResult<Prepared> {
+    let b = &ctx.cfg.build;
+    let collected = collect_phase(ctx)?;
+
+    ctx.environment = collected
+        .plugins
+        .iter()
+        .flat_map(|s| s.environment.iter().cloned())
+        .collect();
+    let mut elves = collected
+        .executables
+        .into_iter()
+        .chain(collected.libraries)
+        .chain(collected.resource_elves)
+        .chain(collected.dlopened.into_iter().map(|d| d.entry))
+        .chain(collected.plugins.iter().flat_map(|s| s.files.clone()))
+        .chain(
+            collected
+                .graph
+                .dependencies()
+                .iter()
+                .map(|d| d.entry.clone()),
+        )
+        .collect::<Vec<_>>();
+
+    // Patched and stripped copies are written here, and must outlive
+    // writing the bundle and its debug archive
+    let rewrite = b.rewrite_runpath.unwrap_or(false);
+    let strip = b.strip.unwrap_or(false);
+    let scratch = if rewrite || strip {
+        Some(TempDir::new("bundle-gen").map_err(|e| BuildError::IO(std::env::temp_dir(), e))?)
+    } else {
+        None
+    };
+    if let Some(ref scratch) = scratch {
+        if rewrite {
+            let dir = scratch.path().join("runpath");
+            for (index, entry) in elves.iter_mut().enumerate() {
+                if let Some(patched) = runpath::rewrite_runpath(entry, &dir, index)? {
+                    ctx.record_processed(entry, &patched, |f| f.runpath_rewritten = true)?;
+                    *entry = patched;
+                }
+            }
+        }
+        if strip {
+            let dir = scratch.path().join("strip");
+            let mut debug_files = Vec::new();
+            for (index, entry) in elves.iter_mut().enumerate() {
+                if let Some((stripped, debug)) = strip::strip_debug(entry, &dir, index)? {
+                    ctx.record_processed(entry, &stripped, |f| f.stripped = true)?;
+                    *entry = stripped;
+                    debug_files.push(debug);
+                }
+            }
+            for debug in debug_files.iter() {
+                if debug.build_id.is_none() {
+                    ctx.build_log.warn(format!(
+                        "{} has no build ID, so crash reports can't be matched to its debug info",
+                        debug.name
+                    ))?;
+                }
+                writeln!(
+                    ctx.build_log,
+                    "Stripped {}, keeping its debug info as {}",
+                    debug.name,
+                    debug.archive_name()
+                )
+                .map_err(BuildError::LogError)?;
+            }
+            ctx.debug_files = debug_files;
+        }
+    }
+    ctx.scratch = scratch;
+
+    Ok(Prepared {
+        elves,
+        resources: collected.resources,
+    })
+}
+
+fn build_phase(ctx: &mut BuildContext) -> BuildResult<(PathBuf, ZipWriter<File>, String)> {
+    let cfg = ctx.cfg;
+    let b = &cfg.build;
+    let stem = ctx.stem;
+    let pc = ctx.pc;
+    let directory = ctx.output.directory.clone().unwrap_or_default();
+    fs::create_dir_all(&directory).map_err(|e| BuildError::IO(directory.clone(), e))?;
+
+    let prepared = prepare_phase(ctx)?;
+
+    ctx.stage(Stage::WritingBundle);
 
     let version = parse_version_file(pc.find_path(&b.version_file)?)?;
-    let output = format!("{}_{}.bundle", stem, version);
-    let f = File::create(output.clone())
-        .map_err(|e| BuildError::IO(Path::new(&output).to_path_buf(), e))?;
+    let id = cfg
+        .store_id
+        .as_ref()
+        .or(cfg.homebrew_id.as_ref())
+        .ok_or(BuildError::BundleOriginUnknown)?;
+    let bundle_type = cfg.bundle_type.to_string();
+    let output = directory.join(expand_output_name(
+        ctx.output.name_template.as_deref().unwrap_or_default(),
+        &[
+            ("stem", stem),
+            ("name", &cfg.name),
+            ("id", id),
+            ("version", &version),
+            ("type", &bundle_type),
+        ],
+    )?);
+
+    // Only verified bundles are given their real name
+    let partial = partial_bundle_path(&output);
+    let f = File::create(&partial).map_err(|e| BuildError::IO(partial.clone(), e))?;
+    ctx.partial_bundle = Some(partial);
     let mut zf = zip::ZipWriter::new(f);
     insert_files(
         &mut zf,
-        &executables_on_disk
+        &prepared
+            .elves
             .into_iter()
-            .chain(libraries_on_disk.into_iter())
-            .chain(resources_on_disk.into_iter())
-            .chain(dependencies_on_disk.into_iter())
+            .chain(prepared.resources)
             .collect::<Vec<_>>(),
     )?;
 
-    Ok((PathBuf::from(output), zf, version))
+    Ok((output, zf, version))
 }
 
+/// Write a script wrapping a startup command. Unless the bundle's ELF
+/// files have had their runpath rewritten, the script also points
+/// `LD_LIBRARY_PATH` at the bundle's libraries. The given environment
+/// variables are set to paths inside the bundle.
 fn make_launcher_sh<W: Write + Seek>(
     zf: &mut ZipWriter<W>,
     name: &str,
     startup_command: &str,
+    library_path: bool,
+    environment: &[(String, String)],
 ) -> BuildResult<()> {
     let options = zip::write::FileOptions::default().unix_permissions(0o755);
     zf.start_file(name, options)?;
@@ -387,6 +1010,17 @@ fn make_launcher_sh<W: Write + Seek>(
         Err(_) => (startup_command.to_string(), Vec::new()),
     };
 
+    let mut export = String::new();
+    if library_path {
+        export.push_str("export LD_LIBRARY_PATH=\"${LD_LIBRARY_PATH}:${P}/lib\"\n");
+    }
+    for (var, path) in environment {
+        export.push_str(&format!("export {}=\"${{P}}/{}\"\n", var, path));
+    }
+    if !export.is_empty() {
+        export.push('\n');
+    }
+
     writeln!(
         zf,
         r#"#!/bin/sh
@@ -395,10 +1029,9 @@ set -x
 
 P=$(dirname "$(busybox realpath "$0")")
 
-export LD_LIBRARY_PATH="${{LD_LIBRARY_PATH}}:${{P}}/lib"
-
-"${{P}}/{}" {} "$@"
+{}"${{P}}/{}" {} "$@"
 "#,
+        export,
         cmd,
         args.join(" ")
     )
@@ -407,18 +1040,60 @@ export LD_LIBRARY_PATH="${{LD_LIBRARY_PATH}}:${{P}}/lib"
     Ok(())
 }
 
-fn make_bundle(cfg: &BundleSpec, stem: &str, pc: &PathContext) -> BuildResult<PathBuf> {
-    let (path, mut zf, version) = build_phase(&cfg.build, stem, pc)?;
+/// Build and verify a bundle, then give it its real name. If anything
+/// fails, nothing is left at the bundle's path.
+fn make_bundle(ctx: &mut BuildContext) -> BuildResult<(PathBuf, String)> {
+    let (path, version) = match write_bundle(ctx) {
+        Ok(res) => res,
+        Err(e) => {
+            if let Some(partial) = ctx.partial_bundle.take() {
+                let _ = fs::remove_file(partial);
+            }
+            return Err(e);
+        }
+    };
+    if let Some(partial) = ctx.partial_bundle.take() {
+        fs::rename(&partial, &path).map_err(|e| BuildError::IO(path.clone(), e))?;
+    }
+
+    // Only keep debug info for bundles that were verified
+    if !ctx.debug_files.is_empty() {
+        let archive = debug_archive_path(&path);
+        strip::write_debug_archive(&archive, &ctx.debug_files)?;
+        ctx.debug_archive = Some(archive);
+    }
+
+    Ok((path, version))
+}
+
+/// Write a bundle under a temporary name, and verify it
+fn write_bundle(ctx: &mut BuildContext) -> BuildResult<(PathBuf, String)> {
+    let cfg = ctx.cfg;
+    let (path, mut zf, version) = build_phase(ctx)?;
+    let library_path = !cfg.build.rewrite_runpath.unwrap_or(false);
 
     let prog = if let Some(ref exec) = cfg.exec {
         if cfg.launcher.is_some() {
             // If some other program will launch us, then startup command
-            // is just our arguments
+            // is just our arguments, and nothing sets up plugin paths
+            if !ctx.environment.is_empty() {
+                let vars = ctx
+                    .environment
+                    .iter()
+                    .map(|(var, value)| format!("{}={}", var, value))
+                    .collect::<Vec<_>>();
+                ctx.build_log.warn(format!(
+                    "no run.sh is generated when Launcher is set, so the launcher must set {} \
+                     (relative to the bundle's root) for bundled plugins to be found",
+                    vars.join(", ")
+                ))?;
+            }
             Some(exec.clone())
         } else {
             // If not, make a simple script to wrap this program and set
             // up its libraries
-            make_launcher_sh(&mut zf, "run.sh", exec)?;
+            make_launcher_sh(&mut zf, "run.sh", exec, library_path, &ctx.environment)?;
+            ctx.record_generated("run.sh");
             Some("run.sh".to_string())
         }
     } else {
@@ -432,7 +1107,7 @@ fn make_bundle(cfg: &BundleSpec, stem: &str, pc: &PathContext) -> BuildResult<Pa
         builder
             .set_exec(prog)
             .set_prefer_xbox_mode(cfg.prefer_xbox_mode)
-            .set_version(Some(version))
+            .set_version(Some(version.clone()))
             .set_requires_launcher(cfg.launcher.clone());
         if cfg.launcher_tags.is_some() {
             return Err(BuildError::InvalidField("LauncherTags".to_string()));
@@ -447,13 +1122,20 @@ fn make_bundle(cfg: &BundleSpec, stem: &str, pc: &PathContext) -> BuildResult<Pa
         builder
             .set_exec(prog)
             .set_prefer_xbox_mode(cfg.prefer_xbox_mode)
-            .set_version(Some(version))
+            .set_version(Some(version.clone()))
             .set_requires_launcher(cfg.launcher.clone())
             .set_background(cfg.background);
 
         if let Some(ref launcher) = cfg.launcher_exec {
             if let Some(ref tags) = cfg.launcher_tags {
-                make_launcher_sh(&mut zf, "launch.sh", launcher)?;
+                make_launcher_sh(
+                    &mut zf,
+                    "launch.sh",
+                    launcher,
+                    library_path,
+                    &ctx.environment,
+                )?;
+                ctx.record_generated("launch.sh");
                 builder.set_provides_launcher(Some("launch.sh".to_string()), tags.clone());
             } else {
                 return Err(BuildError::MissingField("LauncherTags".to_string()));
@@ -467,43 +1149,403 @@ fn make_bundle(cfg: &BundleSpec, stem: &str, pc: &PathContext) -> BuildResult<Pa
     }
 
     if let Some(ref patchfile) = cfg.runner_patch {
-        insert_files(
-            &mut zf,
-            &[FileEntry {
-                name: "runner-patch".to_string(),
-                location: fs::canonicalize(patchfile)
-                    .map_err(|e| BuildError::IO(Path::new(patchfile).to_path_buf(), e))?,
-            }],
-        )?;
+        let patch = [FileEntry {
+            name: "runner-patch".to_string(),
+            location: fs::canonicalize(ctx.pc.find_path(patchfile)?)
+                .map_err(|e| BuildError::IO(Path::new(patchfile).to_path_buf(), e))?,
+        }];
+        insert_files(&mut zf, &patch)?;
+        ctx.record(&patch[0], FileOrigin::RunnerPatch, None)?;
     }
 
     zf.finish()?;
 
-    Ok(path)
+    ctx.stage(Stage::Verifying);
+    let partial = partial_bundle_path(&path);
+    let verified = verify::verify_bundle(&partial, cfg, ctx.resolve)?;
+    for warning in verified.warnings {
+        ctx.build_log.warn(warning)?;
+    }
+    writeln!(ctx.build_log, "Hardening audit:").map_err(BuildError::LogError)?;
+    for audit in verified.audits.iter().filter(|a| !a.findings.is_empty()) {
+        let findings = audit
+            .findings
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        writeln!(ctx.build_log, "  {}: {}", audit.name, findings.join(", "))
+            .map_err(BuildError::LogError)?;
+    }
+
+    Ok((path, version))
+}
+
+/// Generates a bundle from a specification, with all the inputs and
+/// outputs of the build given explicitly.
+///
+/// ```no_run
+/// # use bundle_gen::generate::Generator;
+/// let report = Generator::from_path("/source/game.yaml")?
+///     .working_directory("/build")
+///     .search_location("/build")
+///     .output_directory("/build/out")
+///     .run()?;
+/// println!("{}", report.bundle.to_string_lossy());
+/// # Ok::<(), bundle_gen::generate::BuildError>(())
+/// ```
+pub struct Generator {
+    spec: BundleSpec,
+    stem: String,
+    locations: Vec<PathBuf>,
+    spec_dir: Option<PathBuf>,
+    working_directory: Option<PathBuf>,
+    output: OutputOptions,
+    resolve: ResolveOptions,
+    profile_dirs: Vec<PathBuf>,
+    log_sink: Option<Box<dyn Write + Send>>,
+    progress: Option<ProgressCallback>,
+    warnings: Vec<String>,
+}
+
+impl Generator {
+    /// Create a generator for an already loaded spec. The stem is used
+    /// to name the outputs. No locations are searched for the files the
+    /// spec refers to, unless they are added with
+    /// [`search_location`](Self::search_location).
+    pub fn from_spec<S: Into<String>>(mut spec: BundleSpec, stem: S) -> BuildResult<Self> {
+        // Check for basic errors in the spec
+        BundleSpec::check(&spec)?;
+
+        // Specs written on Windows hosts may use backslashes
+        let warnings = spec.build.normalise_paths();
+
+        Ok(Self {
+            spec,
+            stem: stem.into(),
+            locations: Vec::new(),
+            spec_dir: None,
+            working_directory: None,
+            output: OutputOptions::default(),
+            resolve: ResolveOptions::default(),
+            profile_dirs: Vec::new(),
+            log_sink: None,
+            progress: None,
+            warnings,
+        })
+    }
+
+    /// Load the spec at the given path. The directory containing the
+    /// spec is searched for the files it refers to, after any other
+    /// search locations.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> BuildResult<Self> {
+        let path = path.as_ref();
+        let spec_dir = fs::canonicalize(path)
+            .map_err(|e| BuildError::IO(path.to_path_buf(), e))
+            .and_then(|p| {
+                p.parent()
+                    .map(Path::to_path_buf)
+                    .ok_or(BuildError::NoParent(p))
+            })?;
+        let stem = path
+            .file_stem()
+            .ok_or_else(|| BuildError::ExpectedFile(path.to_path_buf()))?
+            .to_string_lossy()
+            .to_string();
+
+        let mut generator = Self::from_spec(BundleSpec::load(path)?, stem)?;
+        generator.spec_dir = Some(spec_dir);
+        Ok(generator)
+    }
+
+    /// Search the given location for the files the spec refers to. Locations
+    /// are searched in the order they are added, and relative locations
+    /// are resolved against the working directory.
+    pub fn search_location<P: Into<PathBuf>>(mut self, location: P) -> Self {
+        self.locations.push(location.into());
+        self
+    }
+
+    /// Run build commands in this directory, and resolve relative search
+    /// locations and output paths against it. A relative directory is
+    /// itself resolved against the process's current directory, which is
+    /// also the default.
+    pub fn working_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.working_directory = Some(directory.into());
+        self
+    }
+
+    /// Set every output location at once, overriding the spec.
+    pub fn output(mut self, output: OutputOptions) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Write the bundle into this directory, overriding the spec.
+    pub fn output_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.output.directory = Some(directory.into());
+        self
+    }
+
+    /// Name the bundle using this template, overriding the spec.
+    pub fn output_name<S: Into<String>>(mut self, template: S) -> Self {
+        self.output.name_template = Some(template.into());
+        self
+    }
+
+    /// Write the build log to this file, overriding the spec. A relative
+    /// path is resolved against the working directory.
+    pub fn log_file<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.output.log_file = Some(file.into());
+        self
+    }
+
+    /// Set every dependency resolution option at once.
+    pub fn resolve_options(mut self, resolve: ResolveOptions) -> Self {
+        self.resolve = resolve;
+        self
+    }
+
+    /// Use this library cache as the description of the base system.
+    pub fn base_profile<P: Into<PathBuf>>(mut self, profile: P) -> Self {
+        self.resolve.base_profile = Some(profile.into());
+        self
+    }
+
+    /// Search this directory for target profiles named by the spec, before
+    /// the default locations.
+    pub fn profile_directory<P: Into<PathBuf>>(mut self, directory: P) -> Self {
+        self.profile_dirs.push(directory.into());
+        self
+    }
+
+    /// Target this OS release, instead of the one named by the spec.
+    pub fn target_profile(mut self, profile: TargetProfile) -> Self {
+        self.resolve.target_profile = Some(profile);
+        self
+    }
+
+    /// Write the build log here, instead of to a file.
+    pub fn log_sink<W: Write + Send + 'static>(mut self, sink: W) -> Self {
+        self.log_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Call the given function as the build moves between stages.
+    pub fn on_progress<F: FnMut(Stage) + Send + 'static>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Run the build, producing a bundle.
+    pub fn run(mut self) -> BuildResult<BuildReport> {
+        let ((bundle, version), outcome) = self.execute(make_bundle)?;
+
+        let size = fs::metadata(&bundle)
+            .map_err(|e| BuildError::IO(bundle.clone(), e))?
+            .len();
+        let sha256 = report::sha256_file(&bundle).map_err(|e| BuildError::IO(bundle.clone(), e))?;
+
+        Ok(BuildReport {
+            bundle,
+            version,
+            size,
+            sha256,
+            target_profile: self.resolve.target_profile,
+            debug_archive: outcome.debug_archive,
+            log_file: outcome.log_file,
+            files: outcome.files,
+            timings: outcome.timings,
+            warnings: outcome.warnings,
+        })
+    }
+
+    /// Run the build and find everything that would go into the bundle,
+    /// including dependencies, rewriting and stripping ELF files as the
+    /// bundle asks, but don't write the bundle itself.
+    pub fn dry_run(mut self) -> BuildResult<Vec<BundledFile>> {
+        let (_, outcome) = self.execute(prepare_phase)?;
+        Ok(outcome.files)
+    }
+
+    /// Run the build and find the dependency graph of the ELF files it
+    /// produced, without failing if libraries are missing.
+    pub fn dependency_graph(mut self) -> BuildResult<DependencyGraph> {
+        let (collected, _) = self.execute(scan_phase)?;
+        Ok(collected.graph)
+    }
+
+    fn execute<T, F>(&mut self, phase: F) -> BuildResult<(T, Outcome)>
+    where
+        F: FnOnce(&mut BuildContext) -> BuildResult<T>,
+    {
+        // Relative paths are resolved against the working directory,
+        // which is made absolute so nothing later depends on the process's
+        // current directory
+        let base = match self.working_directory {
+            Some(ref wd) if wd.is_absolute() => wd.clone(),
+            ref wd => std::env::current_dir()
+                .map_err(BuildError::EnvIO)?
+                .join(wd.clone().unwrap_or_default()),
+        };
+        let output = self.output.resolve(&self.spec.build, &self.stem, &base);
+
+        if self.resolve.target_profile.is_none() {
+            if let Some(ref name) = self.spec.build.target_profile {
+                self.resolve.target_profile = Some(TargetProfile::find(name, &self.profile_dirs)?);
+            }
+        }
+
+        let (sink, log_file): (Box<dyn Write>, _) = match self.log_sink.take() {
+            Some(sink) => (sink, None),
+            None => {
+                let log_file = output.log_file.clone().unwrap_or_default();
+                if let Some(parent) = log_file.parent() {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| BuildError::IO(parent.to_path_buf(), e))?;
+                }
+                let f = File::create(&log_file).map_err(|e| BuildError::IO(log_file.clone(), e))?;
+                (Box::new(f), Some(log_file))
+            }
+        };
+        let mut build_log = BuildLog::new(sink);
+        for warning in self.warnings.iter() {
+            build_log.warn(warning.clone())?;
+        }
+        if let Some(ref profile) = self.resolve.target_profile {
+            writeln!(build_log, "Target profile: {}", profile.name)
+                .map_err(BuildError::LogError)?;
+            if let Some(ref version) = profile.minimum_os_version {
+                writeln!(build_log, "Minimum OS version: {}", version)
+                    .map_err(BuildError::LogError)?;
+            }
+        }
+
+        let pc = PathContext::new(
+            self.locations
+                .iter()
+                .chain(self.spec_dir.iter())
+                .map(|location| base.join(location))
+                .collect(),
+        );
+        let mut ctx = BuildContext {
+            cfg: &self.spec,
+            stem: &self.stem,
+            pc: &pc,
+            output: &output,
+            resolve: &self.resolve,
+            working_directory: &base,
+            build_log: &mut build_log,
+            progress: self.progress.as_mut(),
+            current_stage: None,
+            timings: Vec::new(),
+            files: BTreeMap::new(),
+            debug_files: Vec::new(),
+            debug_archive: None,
+            partial_bundle: None,
+            scratch: None,
+            environment: Vec::new(),
+        };
+        let res = phase(&mut ctx)?;
+        ctx.stage(Stage::Finished);
+        let timings = ctx.timings;
+        let files = ctx.files.into_values().collect();
+        let debug_archive = ctx.debug_archive;
+
+        build_log.flush().map_err(BuildError::LogError)?;
+
+        Ok((
+            res,
+            Outcome {
+                log_file,
+                files,
+                timings,
+                warnings: build_log.warnings,
+                debug_archive,
+            },
+        ))
+    }
+}
+
+/// What a build leaves behind, besides its result
+struct Outcome {
+    log_file: Option<PathBuf>,
+    files: Vec<BundledFile>,
+    timings: Vec<StageTiming>,
+    warnings: Vec<String>,
+    debug_archive: Option<PathBuf>,
 }
 
-pub fn generate<P: AsRef<Path>>(arg: P) -> BuildResult<PathBuf> {
+/// Generate a bundle from the spec at the given path, searching the
+/// current directory and then the spec's directory for the files it
+/// refers to, and running the build in the current directory.
+pub fn generate<P: AsRef<Path>>(arg: P, output: &OutputOptions) -> BuildResult<BuildReport> {
     let wd = std::env::current_dir().map_err(BuildError::EnvIO)?;
-    let path = PathBuf::from(&arg.as_ref().as_os_str());
-    let spec_dir = fs::canonicalize(&path)
-        .map_err(|e| BuildError::IO(arg.as_ref().to_path_buf(), e))
-        .and_then(|p| {
-            p.parent()
-                .map(Path::to_path_buf)
-                .ok_or(BuildError::NoParent(p))
-        })?;
+    Generator::from_path(arg)?
+        .search_location(&wd)
+        .working_directory(wd)
+        .output(output.clone())
+        .run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKENS: &[(&str, &str)] = &[("stem", "game"), ("version", "1.0"), ("id", "a/b")];
 
-    let pc = PathContext::new(vec![wd, spec_dir]);
+    #[test]
+    fn generator_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Generator>();
+    }
 
-    let spec = BundleSpec::load(&arg)?;
+    #[test]
+    fn relative_log_files_use_the_working_directory() {
+        let spec: BuildSpec = serde_yaml::from_str("VersionFile: version.txt").unwrap();
+        let output = OutputOptions {
+            log_file: Some(PathBuf::from("x.log")),
+            ..Default::default()
+        };
+        let resolved = output.resolve(&spec, "game", Path::new("/build"));
+        assert_eq!(resolved.log_file, Some(PathBuf::from("/build/x.log")));
+        assert_eq!(resolved.directory, Some(PathBuf::from("/build")));
+    }
 
-    // Check for basic errors in the spec
-    BundleSpec::check(&spec)?;
+    #[test]
+    fn expands_tokens() {
+        assert_eq!(
+            expand_output_name("{stem}_{version}.bundle", TOKENS).unwrap(),
+            "game_1.0.bundle"
+        );
+        assert_eq!(
+            expand_output_name("fixed.bundle", TOKENS).unwrap(),
+            "fixed.bundle"
+        );
+        assert_eq!(
+            expand_output_name("{stem}{stem}", TOKENS).unwrap(),
+            "gamegame"
+        );
+    }
 
-    let stem = path
-        .file_stem()
-        .ok_or_else(|| BuildError::ExpectedFile(path.clone()))?
-        .to_string_lossy();
+    #[test]
+    fn values_cannot_add_directories() {
+        assert_eq!(
+            expand_output_name("{id}.bundle", TOKENS).unwrap(),
+            "a_b.bundle"
+        );
+    }
 
-    make_bundle(&spec, &stem, &pc)
+    #[test]
+    fn rejects_bad_templates() {
+        for template in &["{stem", "{nope}.bundle", "", "{}", "out/{stem}.bundle"] {
+            assert!(
+                matches!(
+                    expand_output_name(template, TOKENS),
+                    Err(BuildError::BadTemplate(..))
+                ),
+                "{} should be rejected",
+                template
+            );
+        }
+    }
 }
@@ -0,0 +1,400 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::ldcache::{Dependency, LdError};
+use crate::packages;
+
+/// The formats a dependency graph can be exported in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT, for rendering
+    Dot,
+    /// JSON, for other tools
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("Unknown graph format {}", s)),
+        }
+    }
+}
+
+/// Where a file in the dependency graph comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    /// Listed in the spec, as an executable, library or extra ELF file
+    Own,
+    /// Found while resolving dependencies, and added to the bundle
+    Bundled,
+    /// Provided by the base system, so never bundled
+    BaseSystem,
+    /// Kept out of the bundle by the bundling policy
+    Excluded,
+    /// Needed, but not found anywhere
+    Missing,
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NodeKind::Own => "own",
+            NodeKind::Bundled => "bundled",
+            NodeKind::BaseSystem => "base system",
+            NodeKind::Excluded => "excluded",
+            NodeKind::Missing => "missing",
+        })
+    }
+}
+
+/// A file in the dependency graph
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphNode {
+    /// The bundle entry name for own and bundled files, otherwise the soname
+    pub name: String,
+    pub kind: NodeKind,
+    /// Where the file was found on disk, if it was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+/// A DT_NEEDED entry in the dependency graph
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphEdge<'a> {
+    /// The file with the DT_NEEDED entry
+    pub from: &'a str,
+    /// The file it refers to
+    pub to: &'a str,
+}
+
+#[derive(Serialize)]
+struct GraphListing<'a> {
+    nodes: Vec<&'a GraphNode>,
+    edges: Vec<GraphEdge<'a>>,
+}
+
+/// The ELF files a bundle is built from, and the DT_NEEDED entries
+/// linking them.
+#[derive(Clone, Debug, Default)]
+pub struct DependencyGraph {
+    nodes: BTreeMap<String, GraphNode>,
+    edges: BTreeMap<String, BTreeSet<String>>,
+    dependencies: Vec<Dependency>,
+    allowed_missing: BTreeSet<String>,
+    violations: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl DependencyGraph {
+    pub(crate) fn add_node(&mut self, name: &str, kind: NodeKind, path: Option<PathBuf>) {
+        self.nodes
+            .entry(name.to_string())
+            .or_insert_with(|| GraphNode {
+                name: name.to_string(),
+                kind,
+                path,
+            });
+    }
+
+    pub(crate) fn add_edge(&mut self, from: &str, to: &str) {
+        self.edges
+            .entry(from.to_string())
+            .or_default()
+            .insert(to.to_string());
+    }
+
+    pub(crate) fn add_dependency(&mut self, dependency: Dependency) {
+        self.dependencies.push(dependency);
+    }
+
+    pub(crate) fn allow_missing(&mut self, soname: &str) {
+        self.allowed_missing.insert(soname.to_string());
+    }
+
+    pub(crate) fn add_violation(&mut self, message: String) {
+        self.violations.push(message);
+    }
+
+    pub(crate) fn add_warning(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &GraphNode> {
+        self.nodes.values()
+    }
+
+    pub fn node(&self, name: &str) -> Option<&GraphNode> {
+        self.nodes.get(name)
+    }
+
+    /// Every edge, as the name of the file with the DT_NEEDED entry and
+    /// the name of the file it refers to
+    pub fn edges(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.edges
+            .iter()
+            .flat_map(|(from, to)| to.iter().map(move |to| (from.as_str(), to.as_str())))
+    }
+
+    /// The libraries that must be added to the bundle
+    pub fn dependencies(&self) -> &[Dependency] {
+        &self.dependencies
+    }
+
+    /// The sonames that were needed but not found, and which the
+    /// bundling policy doesn't allow to be missing
+    pub fn missing(&self) -> Vec<String> {
+        self.nodes()
+            .filter(|n| n.kind == NodeKind::Missing && !self.allowed_missing.contains(&n.name))
+            .map(|n| n.name.clone())
+            .collect()
+    }
+
+    /// Problems found while applying the bundling policy that aren't
+    /// serious enough to fail the build
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Check that nothing is missing, and that the bundling policy was
+    /// followed. Missing libraries are reported with the packages that
+    /// might provide them.
+    pub fn check(&self) -> Result<(), LdError> {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            Err(LdError::MissingDependencies(packages::find_packages(
+                &missing,
+            )))
+        } else if !self.violations.is_empty() {
+            Err(LdError::PolicyViolations(self.violations.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The nodes matching a soname, or a bundle entry name
+    pub fn find(&self, soname: &str) -> Vec<&GraphNode> {
+        self.nodes()
+            .filter(|n| {
+                n.name == soname
+                    || Path::new(&n.name).file_name().and_then(|f| f.to_str()) == Some(soname)
+            })
+            .collect()
+    }
+
+    /// The listed files that nothing else needs, which every chain of
+    /// DT_NEEDED entries starts from
+    fn roots(&self) -> Vec<&str> {
+        let needed = self.edges.values().flatten().collect::<BTreeSet<_>>();
+        self.nodes()
+            .filter(|n| n.kind == NodeKind::Own && !needed.contains(&n.name))
+            .map(|n| n.name.as_str())
+            .collect()
+    }
+
+    /// The shortest chain of DT_NEEDED entries from each root to every
+    /// node it leads to, keyed by the node the chains end at
+    pub fn shortest_chains(&self) -> BTreeMap<&str, Vec<Vec<&str>>> {
+        let mut chains = BTreeMap::<&str, Vec<Vec<&str>>>::new();
+        for root in self.roots() {
+            // Breadth first, so the first way found to a node is shortest
+            let mut previous = BTreeMap::<&str, &str>::new();
+            let mut queue = VecDeque::from(vec![root]);
+            while let Some(current) = queue.pop_front() {
+                for next in self.edges.get(current).into_iter().flatten() {
+                    if next != root && !previous.contains_key(next.as_str()) {
+                        previous.insert(next, current);
+                        queue.push_back(next);
+                    }
+                }
+            }
+            for &target in previous.keys() {
+                let mut chain = vec![target];
+                while let Some(&prev) = previous.get(chain[chain.len() - 1]) {
+                    chain.push(prev);
+                }
+                chain.reverse();
+                chains.entry(target).or_default().push(chain);
+            }
+        }
+        chains
+    }
+
+    /// Chains of DT_NEEDED entries leading to the named node, each
+    /// starting from a listed file that nothing else needs. There can be
+    /// very many, so at most `limit` are found.
+    pub fn chains_to(&self, name: &str, limit: usize) -> Vec<Vec<String>> {
+        // Only follow edges towards nodes that lead to the target, so
+        // every step taken finds at least one chain
+        let mut reverse = BTreeMap::<&str, Vec<&str>>::new();
+        for (from, to) in self.edges() {
+            reverse.entry(to).or_default().push(from);
+        }
+        let mut leads_to = BTreeSet::new();
+        let mut pending = vec![name];
+        while let Some(current) = pending.pop() {
+            for &from in reverse.get(current).into_iter().flatten() {
+                if leads_to.insert(from) {
+                    pending.push(from);
+                }
+            }
+        }
+
+        let mut chains = Vec::new();
+        for start in self.roots() {
+            if start != name && leads_to.contains(start) {
+                let mut path = vec![start.to_string()];
+                self.walk(name, &leads_to, limit, &mut path, &mut chains);
+            }
+        }
+        chains
+    }
+
+    fn walk(
+        &self,
+        target: &str,
+        leads_to: &BTreeSet<&str>,
+        limit: usize,
+        path: &mut Vec<String>,
+        chains: &mut Vec<Vec<String>>,
+    ) {
+        let current = path.last().cloned().unwrap_or_default();
+        for n in self.edges.get(&current).into_iter().flatten() {
+            if chains.len() >= limit {
+                return;
+            }
+            if n == target {
+                let mut chain = path.clone();
+                chain.push(n.clone());
+                chains.push(chain);
+            } else if leads_to.contains(n.as_str()) && !path.contains(n) {
+                path.push(n.clone());
+                self.walk(target, leads_to, limit, path, chains);
+                path.pop();
+            }
+        }
+    }
+
+    /// Export the graph in the given format
+    pub fn export(&self, format: GraphFormat) -> serde_json::Result<String> {
+        match format {
+            GraphFormat::Dot => Ok(self.to_dot()),
+            GraphFormat::Json => self.to_json().map(|json| json + "\n"),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(&GraphListing {
+            nodes: self.nodes().collect(),
+            edges: self
+                .edges()
+                .map(|(from, to)| GraphEdge { from, to })
+                .collect(),
+        })
+    }
+
+    /// Write the graph in Graphviz DOT format, shading nodes by kind
+    pub fn to_dot(&self) -> String {
+        let mut res = String::from("digraph dependencies {\n    rankdir=LR;\n");
+        for node in self.nodes() {
+            let style = match node.kind {
+                NodeKind::Own => "shape=box",
+                NodeKind::Bundled => "style=filled, fillcolor=lightblue",
+                NodeKind::BaseSystem => "style=filled, fillcolor=lightgrey",
+                NodeKind::Excluded => "style=filled, fillcolor=orange",
+                NodeKind::Missing => "style=filled, fillcolor=red",
+            };
+            let _ = writeln!(
+                res,
+                "    {} [tooltip=\"{}\", {}];",
+                dot_id(&node.name),
+                node.kind,
+                style
+            );
+        }
+        for (from, to) in self.edges() {
+            let _ = writeln!(res, "    {} -> {};", dot_id(from), dot_id(to));
+        }
+        res.push_str("}\n");
+        res
+    }
+}
+
+/// Quote a name for use as a DOT identifier
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A game needing two libraries, each needing both of the next
+    /// pair, and so on, so there are 2^depth chains to the last library
+    fn lattice(depth: usize) -> DependencyGraph {
+        let mut graph = DependencyGraph::default();
+        graph.add_node("bin/game", NodeKind::Own, None);
+        let mut previous = vec!["bin/game".to_string()];
+        for level in 0..depth {
+            let current = vec![format!("liba{}.so", level), format!("libb{}.so", level)];
+            for name in current.iter() {
+                graph.add_node(name, NodeKind::Bundled, None);
+                for from in previous.iter() {
+                    graph.add_edge(from, name);
+                }
+            }
+            previous = current;
+        }
+        graph.add_node("libend.so", NodeKind::Bundled, None);
+        for from in previous.iter() {
+            graph.add_edge(from, "libend.so");
+        }
+        graph
+    }
+
+    #[test]
+    fn shortest_chains_has_one_per_root() {
+        let mut graph = lattice(40);
+        graph.add_node("bin/tool", NodeKind::Own, None);
+        graph.add_edge("bin/tool", "libb39.so");
+
+        let chains = graph.shortest_chains();
+        let end = &chains["libend.so"];
+        assert_eq!(end.len(), 2);
+        assert_eq!(end[0].len(), 42);
+        assert_eq!(end[0][0], "bin/game");
+        assert_eq!(end[1], ["bin/tool", "libb39.so", "libend.so"]);
+        assert!(!chains.contains_key("bin/game"));
+    }
+
+    #[test]
+    fn chains_to_stops_at_the_limit() {
+        let graph = lattice(40);
+        assert_eq!(graph.chains_to("libend.so", 10).len(), 10);
+        assert_eq!(lattice(3).chains_to("libend.so", 100).len(), 8);
+    }
+
+    #[test]
+    fn chains_to_skips_unrelated_branches() {
+        let mut graph = lattice(2);
+        graph.add_node("libother.so", NodeKind::Bundled, None);
+        graph.add_edge("bin/game", "libother.so");
+        let chains = graph.chains_to("liba0.so", 100);
+        assert_eq!(chains, [["bin/game", "liba0.so"]]);
+        assert!(graph.chains_to("bin/game", 100).is_empty());
+    }
+}
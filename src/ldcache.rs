@@ -6,11 +6,20 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsString;
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
+use goblin::elf::dynamic::{DT_RPATH, DT_RUNPATH};
+use goblin::elf::header::ET_DYN;
+use goblin::elf::Elf;
 use log::{debug, error, trace};
 use thiserror::Error;
 
+use crate::baselibs::{self, BaseLibraries};
+use crate::graph::{DependencyGraph, NodeKind};
+use crate::packages::{self, MissingLibrary};
+use crate::policy::{self, Policy, PolicyAction, PolicyError};
+use crate::profile::TargetProfile;
 use crate::FileEntry;
 
 #[derive(Debug, Error)]
@@ -21,40 +30,281 @@ pub enum LdError {
     Elf(#[from] goblin::error::Error),
     #[error("Cache error: {0}")]
     Cache(#[from] ldcache_rs::CacheError),
+    #[error("Base library listing error: {0}")]
+    Listing(#[from] serde_json::Error),
+    #[error("Malformed base library listing: {0}")]
+    BadListing(String),
     #[error("Not an ELF-format archive")]
     NotElf,
-    #[error("Missing dependencies: {}", .0.join(", "))]
-    MissingDependencies(Vec<String>),
+    #[error("Missing dependencies: {}", packages::describe(.0))]
+    MissingDependencies(Vec<MissingLibrary>),
+    #[error(
+        "Base system library profile {} not found; choose one with --base-profile or {}",
+        .0.to_string_lossy(),
+        BASE_PROFILE_ENV
+    )]
+    MissingBaseProfile(PathBuf),
+    #[error("Unable to load bundling policy: {0}")]
+    Policy(#[from] PolicyError),
+    #[error("Bundling policy violated: {}", .0.join("; "))]
+    PolicyViolations(Vec<String>),
 }
 
-fn find_elf_deps<P: AsRef<Path>>(item: P) -> Result<Vec<String>, LdError> {
+/// The base system library profile used if no other is given
+pub const DEFAULT_BASE_PROFILE: &str = "/usr/local/share/bundle-gen/ld.so.cache.vcs";
+/// The environment variable that can be used to choose a base profile
+pub const BASE_PROFILE_ENV: &str = "BUNDLE_GEN_BASE_PROFILE";
+
+/// Options controlling how dependencies are resolved
+#[derive(Clone, Debug, Default)]
+pub struct ResolveOptions {
+    /// The library cache describing the base system. If not given, the
+    /// target profile's base libraries are used, then the value of
+    /// `BUNDLE_GEN_BASE_PROFILE`, or failing that [`DEFAULT_BASE_PROFILE`].
+    pub base_profile: Option<PathBuf>,
+    /// The OS release being targeted
+    pub target_profile: Option<TargetProfile>,
+    /// The policy deciding which dependencies may be bundled. If not
+    /// given, the target profile's policy is used, then the value of
+    /// `BUNDLE_GEN_POLICY`, or failing that [`policy::DEFAULT_POLICY`] if
+    /// it exists.
+    pub policy: Option<PathBuf>,
+    /// Extra directories to search for libraries. Like `LD_LIBRARY_PATH`,
+    /// these are searched after an ELF file's DT_RPATH, and before its
+    /// DT_RUNPATH and the build system's library cache.
+    pub library_search_paths: Vec<PathBuf>,
+}
+
+impl ResolveOptions {
+    /// The base system library profile these options select
+    pub fn base_profile(&self) -> PathBuf {
+        self.base_profile
+            .clone()
+            .or_else(|| {
+                self.target_profile
+                    .as_ref()
+                    .map(|p| p.base_libraries.clone())
+            })
+            .or_else(|| std::env::var_os(BASE_PROFILE_ENV).map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_BASE_PROFILE))
+    }
+
+    /// Load the base system library profile these options select
+    pub fn load_base_deps(&self) -> Result<BaseLibraries, LdError> {
+        load_base_deps(self.base_profile())
+    }
+
+    /// The bundling policy these options select, if any
+    pub fn policy(&self) -> Option<PathBuf> {
+        self.policy
+            .clone()
+            .or_else(|| self.target_profile.as_ref().and_then(|p| p.policy.clone()))
+            .or_else(|| std::env::var_os(policy::POLICY_ENV).map(PathBuf::from))
+            .or_else(|| {
+                let default = PathBuf::from(policy::DEFAULT_POLICY);
+                if default.exists() {
+                    Some(default)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Load the bundling policy these options select, which permits
+    /// everything if there is none
+    pub fn load_policy(&self) -> Result<Policy, LdError> {
+        Ok(match self.policy() {
+            Some(path) => Policy::load(path)?,
+            None => Policy::default(),
+        })
+    }
+}
+
+/// A library that a bundle needs, but which wasn't listed in its spec
+#[derive(Clone, Debug)]
+pub struct Dependency {
+    /// Where the library is found, and where it goes in the bundle
+    pub entry: FileEntry,
+    /// The name the library was requested by
+    pub soname: String,
+    /// The ELF file whose dependency this is
+    pub needed_by: PathBuf,
+}
+
+/// The parts of an ELF file's dynamic section that affect how its
+/// dependencies are found
+#[derive(Debug, Default)]
+struct DynamicInfo {
+    needed: Vec<String>,
+    rpath: Vec<String>,
+    runpath: Vec<String>,
+}
+
+fn find_elf_deps<P: AsRef<Path>>(item: P) -> Result<DynamicInfo, LdError> {
     let buf = fs::read(item)?;
-    let e = goblin::Object::parse(&buf)?;
+    read_dynamic(&buf)
+}
+
+fn read_dynamic(buf: &[u8]) -> Result<DynamicInfo, LdError> {
+    let e = goblin::Object::parse(buf)?;
     let e = if let goblin::Object::Elf(e) = e {
         Ok(e)
     } else {
         Err(LdError::NotElf)
     }?;
 
-    Ok(if let Some(d) = e.dynamic {
-        d.get_libraries(&e.dynstrtab)
+    let mut info = DynamicInfo::default();
+    if let Some(d) = e.dynamic {
+        info.needed = d
+            .get_libraries(&e.dynstrtab)
             .into_iter()
             .map(str::to_owned)
-            .collect()
-    } else {
-        Vec::new()
+            .collect();
+        for entry in d.dyns.iter() {
+            let paths = match entry.d_tag {
+                DT_RPATH => &mut info.rpath,
+                DT_RUNPATH => &mut info.runpath,
+                _ => continue,
+            };
+            if let Some(value) = e.dynstrtab.get_at(entry.d_val as usize) {
+                paths.extend(
+                    value
+                        .split(':')
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_owned),
+                );
+            }
+        }
+    }
+    Ok(info)
+}
+
+/// The bytes every ELF file starts with
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+/// Whether a file is a dynamically linked ELF shared object, like a
+/// plugin or a position independent executable. Only the start of the
+/// file is read unless it is an ELF file.
+pub fn is_shared_object<P: AsRef<Path>>(path: P) -> Result<bool, LdError> {
+    let mut magic = [0u8; 4];
+    let mut file = fs::File::open(path.as_ref())?;
+    if file.read_exact(&mut magic).is_err() || magic != *ELF_MAGIC {
+        return Ok(false);
+    }
+    let buf = fs::read(path)?;
+    Ok(matches!(
+        goblin::Object::parse(&buf),
+        Ok(goblin::Object::Elf(elf)) if elf.header.e_type == ET_DYN && elf.dynamic.is_some()
+    ))
+}
+
+/// The symbol versions an ELF file defines, and those it needs from
+/// each of its dependencies
+#[derive(Debug, Default)]
+pub struct SymbolVersions {
+    pub defined: BTreeSet<String>,
+    /// The versions needed, keyed by the soname providing them
+    pub needed: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// The version definition flag marking the entry that names the file itself
+const VER_FLG_BASE: u16 = 0x1;
+
+/// The symbol versions defined in an ELF file's `.gnu.version_d`
+pub(crate) fn defined_versions(elf: &Elf) -> BTreeSet<String> {
+    let mut defined = BTreeSet::new();
+    if let Some(ref verdef) = elf.verdef {
+        for def in verdef.iter() {
+            if def.vd_flags & VER_FLG_BASE != 0 {
+                continue;
+            }
+            if let Some(name) = def
+                .iter()
+                .next()
+                .and_then(|aux| elf.dynstrtab.get_at(aux.vda_name))
+            {
+                defined.insert(name.to_string());
+            }
+        }
+    }
+    defined
+}
+
+/// Read the symbol versions an ELF file held in memory defines, and
+/// those its `.gnu.version_r` says it needs
+pub fn elf_symbol_versions(buf: &[u8]) -> Result<SymbolVersions, LdError> {
+    let elf = match goblin::Object::parse(buf)? {
+        goblin::Object::Elf(elf) => elf,
+        _ => return Err(LdError::NotElf),
+    };
+
+    let mut needed = BTreeMap::<String, BTreeSet<String>>::new();
+    if let Some(ref verneed) = elf.verneed {
+        for need in verneed.iter() {
+            if let Some(file) = elf.dynstrtab.get_at(need.vn_file) {
+                let versions = needed.entry(file.to_string()).or_default();
+                versions.extend(
+                    need.iter()
+                        .filter_map(|aux| elf.dynstrtab.get_at(aux.vna_name))
+                        .map(str::to_string),
+                );
+            }
+        }
+    }
+
+    Ok(SymbolVersions {
+        defined: defined_versions(&elf),
+        needed,
     })
 }
 
-fn load_base_deps<P: AsRef<Path>>(f: P) -> Result<ldcache_rs::Cache, LdError> {
+/// List the DT_NEEDED entries of an ELF file held in memory
+pub fn elf_deps(buf: &[u8]) -> Result<Vec<String>, LdError> {
+    Ok(read_dynamic(buf)?.needed)
+}
+
+/// Expand the directories of a DT_RPATH or DT_RUNPATH entry, replacing
+/// `$ORIGIN` with the directory holding the ELF file. Directories using
+/// other substitutions are skipped, as they depend on the device.
+fn expand_search_path(paths: &[String], item: &Path) -> Vec<PathBuf> {
+    let origin = item.parent().unwrap_or_else(|| Path::new("/"));
+    paths
+        .iter()
+        .filter_map(|p| {
+            let expanded = p
+                .replace("${ORIGIN}", &origin.to_string_lossy())
+                .replace("$ORIGIN", &origin.to_string_lossy());
+            if expanded.contains('$') {
+                trace!("Skipping search path {} of {}", p, item.to_string_lossy());
+                None
+            } else {
+                Some(PathBuf::from(expanded))
+            }
+        })
+        .collect()
+}
+
+/// Find a library in the first of the given directories that has it
+fn search_dirs<'a, I>(dirs: I, soname: &str) -> Option<PathBuf>
+where
+    I: IntoIterator<Item = &'a PathBuf>,
+{
+    dirs.into_iter()
+        .map(|dir| dir.join(soname))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Load a base system's libraries, from a binary `ld.so.cache` or a text
+/// or JSON listing.
+pub fn load_base_deps<P: AsRef<Path>>(f: P) -> Result<BaseLibraries, LdError> {
+    if !f.as_ref().exists() {
+        return Err(LdError::MissingBaseProfile(f.as_ref().to_path_buf()));
+    }
     let buf = fs::read(f.as_ref()).map_err(|e| {
         error!("Unable to open ldcache with fs::read: {:?}", e);
         e
     })?;
-    Ok(ldcache_rs::Cache::parse(
-        &buf,
-        ldcache_rs::TargetEndian::Native,
-    )?)
+    BaseLibraries::parse(&buf)
 }
 
 fn find_additional_versions(
@@ -109,61 +359,188 @@ fn find_additional_versions(
     Ok(())
 }
 
-pub fn resolve_deps(elves: Vec<FileEntry>) -> Result<Vec<FileEntry>, LdError> {
-    let base_deps = load_base_deps("/usr/local/share/bundle-gen/ld.so.cache.vcs").map_err(|e| {
+/// Find the dependencies of the given ELF files, returning the libraries
+/// that must be bundled with them.
+pub fn resolve_deps(
+    elves: Vec<FileEntry>,
+    options: &ResolveOptions,
+) -> Result<Vec<Dependency>, LdError> {
+    let graph = dependency_graph(elves, options)?;
+    graph.check()?;
+    Ok(graph.dependencies().to_vec())
+}
+
+/// Build the graph of DT_NEEDED entries reachable from the given ELF
+/// files, applying the bundling policy. Libraries that can't be found,
+/// and breaches of the policy, are recorded in the graph rather than
+/// being treated as errors.
+pub fn dependency_graph(
+    elves: Vec<FileEntry>,
+    options: &ResolveOptions,
+) -> Result<DependencyGraph, LdError> {
+    let base_deps = options.load_base_deps().map_err(|e| {
         error!("Unable to load ldcache: {:?}", e);
         e
     })?;
+    let policy = options.load_policy()?;
     let build_deps = ldcache_rs::Cache::new()?;
-    let mut work = Vec::<PathBuf>::new();
-    let mut queued = BTreeSet::new();
-    let mut own_libs = BTreeSet::new();
+    let mut graph = DependencyGraph::default();
+    // Each file to process, with the DT_RPATH directories of the files
+    // that led to it, which the loader also searches
+    let mut work = Vec::<(PathBuf, Vec<PathBuf>)>::new();
+    // The graph node for each file we've queued
+    let mut queued = BTreeMap::<PathBuf, String>::new();
+    let mut own_libs = BTreeMap::new();
     let mut own_extra_libs = BTreeMap::new();
-    let mut res = Vec::new();
 
+    let own_elves = elves.clone();
     for elf in elves {
         let pb = elf.location.clone();
-        if !queued.contains(&pb) {
+        if !queued.contains_key(&pb) {
             if let Some(file) = Path::new(&elf.name).file_name() {
-                own_libs.insert(file.to_os_string());
+                own_libs.insert(file.to_os_string(), elf.name.clone());
             }
             find_additional_versions(&elf, &mut own_extra_libs)?;
-            queued.insert(pb.clone());
-            work.push(pb);
+            graph.add_node(&elf.name, NodeKind::Own, Some(pb.clone()));
+            queued.insert(pb.clone(), elf.name.clone());
+            work.push((pb, Vec::new()));
+        }
+    }
+
+    // Anything bundled in lib/ is found before the base system's copy,
+    // including by base system components the bundle runs
+    let mut shadowing = own_extra_libs
+        .iter()
+        .map(|(soname, entry)| (soname.clone(), entry))
+        .collect::<BTreeMap<_, _>>();
+    for entry in own_elves.iter() {
+        if let Some(file) = Path::new(&entry.name).file_name() {
+            shadowing.insert(file.to_os_string(), entry);
+        }
+    }
+    for (soname, entry) in shadowing {
+        let soname = soname.to_string_lossy();
+        let base = match base_deps.get(&soname) {
+            Some(base) if Path::new(&entry.name).parent() == Some(Path::new("lib")) => base,
+            _ => continue,
+        };
+        if policy.always_bundles(&soname) {
+            continue;
+        }
+        let describe = |version: Option<String>| {
+            version
+                .map(|v| format!(" (version {})", v))
+                .unwrap_or_default()
+        };
+        let (action, reason) = policy.shadowing(&soname);
+        let message = format!(
+            "{}{} takes the place of the base system's {}{}{}",
+            entry.name,
+            describe(baselibs::library_version(&entry.location, &soname)),
+            soname,
+            describe(base.version.clone()),
+            reason.map(|r| format!(": {}", r)).unwrap_or_default()
+        );
+        match action {
+            PolicyAction::Error => graph.add_violation(message),
+            PolicyAction::Warn => graph.add_warning(message),
         }
     }
 
-    let mut missing_deps = BTreeSet::new();
-    while let Some(item) = work.pop() {
+    while let Some((item, inherited_rpath)) = work.pop() {
         trace!("Processing {} for dependencies", item.to_string_lossy());
+        let node = queued[&item].clone();
         match find_elf_deps(&item) {
-            Ok(deps) => {
-                for d in deps {
+            Ok(info) => {
+                // DT_RPATH is ignored when DT_RUNPATH is present, and is
+                // otherwise searched along with that of the files leading here
+                let rpath = if info.runpath.is_empty() {
+                    expand_search_path(&info.rpath, &item)
+                        .into_iter()
+                        .chain(inherited_rpath)
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                let runpath = expand_search_path(&info.runpath, &item);
+                for d in info.needed {
                     trace!(" - {}", d);
-                    if !base_deps.contains(&d) && !own_libs.contains(&OsString::from(&d)) {
-                        if let Some(entry) = own_extra_libs.get(&OsString::from(&d)) {
-                            res.push(entry.clone());
-                            own_libs.insert(OsString::from(d));
-                        } else {
-                            match build_deps.get_path(&d) {
-                                Some(p) => {
-                                    let p: &Path = p.as_ref();
-                                    let p = p.to_path_buf();
-                                    if !queued.contains(&p) {
-                                        queued.insert(p.clone());
-                                        res.push(FileEntry {
-                                            name: Path::new("lib")
-                                                .join(&d)
-                                                .to_string_lossy()
-                                                .to_string(),
-                                            location: p.clone(),
-                                        });
-                                        work.push(p);
+                    let found = search_dirs(
+                        rpath
+                            .iter()
+                            .chain(options.library_search_paths.iter())
+                            .chain(runpath.iter()),
+                        &d,
+                    )
+                    .or_else(|| {
+                        build_deps.get_path(&d).map(|p| {
+                            let p: &Path = p.as_ref();
+                            p.to_path_buf()
+                        })
+                    });
+                    let in_base =
+                        base_deps.contains(&d) && !(policy.always_bundles(&d) && found.is_some());
+                    if in_base {
+                        graph.add_node(&d, NodeKind::BaseSystem, None);
+                        graph.add_edge(&node, &d);
+                    } else if let Some(own) = own_libs.get(&OsString::from(&d)) {
+                        graph.add_edge(&node, own);
+                    } else if let Some(entry) = own_extra_libs.get(&OsString::from(&d)) {
+                        let own = queued[&entry.location].clone();
+                        graph.add_dependency(Dependency {
+                            entry: entry.clone(),
+                            soname: d.clone(),
+                            needed_by: item.clone(),
+                        });
+                        graph.add_edge(&node, &own);
+                        own_libs.insert(OsString::from(d), own);
+                    } else {
+                        match found {
+                            Some(p) => {
+                                let name = Path::new("lib").join(&d).to_string_lossy().to_string();
+                                if let Some(existing) = queued.get(&p) {
+                                    graph.add_edge(&node, existing);
+                                } else if graph.node(&name).is_some() {
+                                    // Already found by another route, such
+                                    // as a different search path
+                                    graph.add_edge(&node, &name);
+                                } else if let Some(rule) = policy.never_bundle(&d) {
+                                    let message = format!(
+                                        "{} needs {}, which must not be bundled{}",
+                                        node,
+                                        d,
+                                        rule.reason
+                                            .as_ref()
+                                            .map(|r| format!(": {}", r))
+                                            .unwrap_or_default()
+                                    );
+                                    match rule.action {
+                                        PolicyAction::Error => graph.add_violation(message),
+                                        PolicyAction::Warn => graph.add_warning(message),
                                     }
+                                    graph.add_node(&d, NodeKind::Excluded, Some(p.clone()));
+                                    graph.add_edge(&node, &d);
+                                } else {
+                                    queued.insert(p.clone(), name.clone());
+                                    graph.add_node(&name, NodeKind::Bundled, Some(p.clone()));
+                                    graph.add_edge(&node, &name);
+                                    graph.add_dependency(Dependency {
+                                        entry: FileEntry {
+                                            name,
+                                            location: p.clone(),
+                                        },
+                                        soname: d.clone(),
+                                        needed_by: item.clone(),
+                                    });
+                                    work.push((p, rpath.clone()));
                                 }
-                                None => {
-                                    missing_deps.insert(d);
+                            }
+                            None => {
+                                if policy.allows_missing(&d) {
+                                    graph.allow_missing(&d);
                                 }
+                                graph.add_node(&d, NodeKind::Missing, None);
+                                graph.add_edge(&node, &d);
                             }
                         }
                     }
@@ -177,19 +554,17 @@ pub fn resolve_deps(elves: Vec<FileEntry>) -> Result<Vec<FileEntry>, LdError> {
         }
     }
 
-    if !missing_deps.is_empty() {
+    let missing = graph.missing();
+    if !missing.is_empty() {
         debug!("Missing dependencies:");
-        for d in missing_deps.iter() {
+        for d in missing.iter() {
             debug!(" - {}", d);
         }
         debug!("Own libs are:");
-        for dep in own_libs {
+        for dep in own_libs.keys() {
             debug!(" - {}", dep.to_string_lossy());
         }
-        return Err(LdError::MissingDependencies(
-            missing_deps.into_iter().collect(),
-        ));
     }
 
-    Ok(res)
+    Ok(graph)
 }
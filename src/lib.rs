@@ -3,11 +3,26 @@
  *
  * SPDX-License-Identifier: MIT
  */
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+pub mod abi;
+pub mod audit;
+pub mod baselibs;
 pub mod config;
+pub mod dlopen;
+pub mod frameworks;
 pub mod generate;
+pub mod graph;
 pub mod ldcache;
+pub mod packages;
+pub mod policy;
+pub mod profile;
+pub mod report;
+pub mod runpath;
+pub mod strip;
+pub mod validate;
+pub mod verify;
 
 /// An item waiting to be written to a bundle
 #[derive(Clone, Debug)]
@@ -17,3 +32,18 @@ pub struct FileEntry {
     /// The item's destination path in the bundle
     pub name: String,
 }
+
+impl FileEntry {
+    /// Where to write a modified copy of this entry in a scratch
+    /// directory, creating the directory it goes in. Entries may share a
+    /// file name, so each index gets its own directory.
+    pub(crate) fn scratch_copy(&self, scratch: &Path, index: usize) -> std::io::Result<PathBuf> {
+        let dir = scratch.join(index.to_string());
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join(
+            Path::new(&self.name)
+                .file_name()
+                .unwrap_or_else(|| self.name.as_ref()),
+        ))
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{debug, trace};
+
+/// Where dpkg records the files each installed package provides
+pub const DPKG_INFO_DIR: &str = "/var/lib/dpkg/info";
+/// Where apt keeps its indexes, including any `Contents` indexes
+/// downloaded by `apt-file update`
+pub const APT_LISTS_DIR: &str = "/var/lib/apt/lists";
+/// The apt helper that can decompress any index apt can
+const APT_HELPER: &str = "/usr/lib/apt/apt-helper";
+
+/// A library that couldn't be found, with the packages that might
+/// provide it
+#[derive(Clone, Debug, Default)]
+pub struct MissingLibrary {
+    pub soname: String,
+    /// Installed packages with a file of this name, and where it is
+    pub installed: Vec<(String, PathBuf)>,
+    /// Packages in the apt repositories with a file of this name
+    pub available: BTreeSet<String>,
+}
+
+impl fmt::Display for MissingLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.soname)?;
+        let available = self.available.iter().cloned().collect::<Vec<_>>();
+        if let Some((package, path)) = self.installed.first() {
+            write!(
+                f,
+                " (installed by {} at {}; add its directory to LibrarySearchPaths)",
+                package,
+                path.to_string_lossy()
+            )
+        } else if available.len() == 1 {
+            write!(f, " (add {} to RequiredPackages)", available[0])
+        } else if !available.is_empty() {
+            write!(
+                f,
+                " (add one of {} to RequiredPackages)",
+                available.join(", ")
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Describe missing libraries for an error message
+pub fn describe(missing: &[MissingLibrary]) -> String {
+    missing
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The sonames the file at a path would satisfy
+fn soname_of(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Call a function with each line of a plain or compressed index,
+/// decompressing it with apt's helper
+fn read_index<F: FnMut(&str)>(path: &Path, mut f: F) {
+    let compressed = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("lz4") | Some("gz") | Some("xz") | Some("bz2") | Some("zst") | Some("lzma")
+    );
+    if !compressed {
+        match File::open(path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .for_each(|line| f(&line)),
+            Err(e) => debug!("Unable to open {}: {}", path.to_string_lossy(), e),
+        }
+        return;
+    }
+
+    let mut child = match Command::new(APT_HELPER)
+        .arg("cat-file")
+        .arg(path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            debug!("Unable to run {}: {}", APT_HELPER, e);
+            return;
+        }
+    };
+    if let Some(stdout) = child.stdout.take() {
+        BufReader::new(stdout)
+            .lines()
+            .map_while(Result::ok)
+            .for_each(|line| f(&line));
+    }
+    let _ = child.wait();
+}
+
+/// The files in a directory whose names satisfy a predicate
+fn files_in(dir: &str, predicate: impl Fn(&str) -> bool) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| {
+                    matches!(p.file_name().and_then(|n| n.to_str()), Some(name) if predicate(name))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// Look through dpkg's list of the files each installed package has
+fn search_dpkg(missing: &mut BTreeMap<String, MissingLibrary>) {
+    for list in files_in(DPKG_INFO_DIR, |name| name.ends_with(".list")) {
+        let package = list
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default();
+        // Multiarch packages are listed as package:arch
+        let package = package.split(':').next().unwrap_or_default().to_string();
+        read_index(&list, |line| {
+            if let Some(library) = missing.get_mut(soname_of(line)) {
+                trace!("{} has {}", package, line);
+                library
+                    .installed
+                    .push((package.clone(), PathBuf::from(line)));
+            }
+        });
+    }
+}
+
+/// Look through any `Contents` indexes apt has, which list the files in
+/// every package in the repositories. Each line is a path, then
+/// whitespace, then a comma separated list of `section/package`.
+fn search_contents(missing: &mut BTreeMap<String, MissingLibrary>) {
+    for index in files_in(APT_LISTS_DIR, |name| name.contains("Contents-")) {
+        trace!("Searching {}", index.to_string_lossy());
+        read_index(&index, |line| {
+            let (path, packages) = match line.trim_end().rsplit_once(char::is_whitespace) {
+                Some(split) => split,
+                None => return,
+            };
+            if let Some(library) = missing.get_mut(soname_of(path.trim_end())) {
+                library.available.extend(
+                    packages
+                        .split(',')
+                        .map(|p| p.rsplit('/').next().unwrap_or(p).to_string()),
+                );
+            }
+        });
+    }
+}
+
+/// Find the packages that might provide each of the given sonames,
+/// using only the local dpkg database and apt indexes. Sources that
+/// can't be read are skipped.
+pub fn find_packages(sonames: &[String]) -> Vec<MissingLibrary> {
+    let mut missing = sonames
+        .iter()
+        .map(|soname| {
+            (
+                soname.clone(),
+                MissingLibrary {
+                    soname: soname.clone(),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    search_dpkg(&mut missing);
+    search_contents(&mut missing);
+    missing.into_values().collect()
+}
@@ -0,0 +1,206 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::audit::Finding;
+
+/// The policy used if no other is given, when it exists
+pub const DEFAULT_POLICY: &str = "/usr/local/share/bundle-gen/policy.vcs.yaml";
+/// The environment variable that can be used to choose a policy
+pub const POLICY_ENV: &str = "BUNDLE_GEN_POLICY";
+
+#[derive(Debug, Error)]
+pub enum PolicyError {
+    #[error("IO error opening policy {}: {1}", .0.to_string_lossy())]
+    Io(PathBuf, std::io::Error),
+    #[error("YAML parse error in policy {}: {1}", .0.to_string_lossy())]
+    Yaml(PathBuf, serde_yaml::Error),
+}
+
+/// What to do when a library that must never be bundled is needed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PolicyAction {
+    /// Fail the build
+    Error,
+    /// Leave the library out of the bundle, and warn about it
+    Warn,
+}
+
+/// Libraries that must never be bundled, because a private copy would
+/// break when the device is updated
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct NeverBundle {
+    /// A soname pattern, where `*` matches any run of characters and `?`
+    /// matches any one character
+    pub pattern: String,
+    pub action: PolicyAction,
+    /// Why the library must not be bundled, for the error message
+    pub reason: Option<String>,
+}
+
+/// Rules deciding which dependencies may be bundled automatically
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub never_bundle: Vec<NeverBundle>,
+    /// Patterns for libraries to bundle, even if the base system has them
+    #[serde(default)]
+    pub always_bundle: Vec<String>,
+    /// Patterns for libraries that may be missing, usually because they
+    /// are only loaded when present
+    #[serde(default)]
+    pub allowed_missing: Vec<String>,
+    /// What to do when a bundled library has the same name as one in
+    /// the base system, and so takes its place; warns if not given
+    pub shadowing: Option<PolicyAction>,
+    /// Hardening audit findings that fail the build
+    #[serde(default)]
+    pub hardening_failures: Vec<Finding>,
+}
+
+impl Policy {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Policy, PolicyError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| PolicyError::Io(path.to_path_buf(), e))?;
+        serde_yaml::from_reader(file).map_err(|e| PolicyError::Yaml(path.to_path_buf(), e))
+    }
+
+    /// The first never-bundle rule matching a soname
+    pub fn never_bundle(&self, soname: &str) -> Option<&NeverBundle> {
+        self.never_bundle
+            .iter()
+            .find(|rule| pattern_matches(&rule.pattern, soname))
+    }
+
+    pub fn always_bundles(&self, soname: &str) -> bool {
+        self.always_bundle
+            .iter()
+            .any(|pattern| pattern_matches(pattern, soname))
+    }
+
+    /// What to do when a bundled library takes the place of a base
+    /// system one, and why, if the never-bundle rules give a reason
+    pub fn shadowing(&self, soname: &str) -> (PolicyAction, Option<&str>) {
+        match self.never_bundle(soname) {
+            Some(rule) => (rule.action, rule.reason.as_deref()),
+            None => (self.shadowing.unwrap_or(PolicyAction::Warn), None),
+        }
+    }
+
+    pub fn allows_missing(&self, soname: &str) -> bool {
+        self.allowed_missing
+            .iter()
+            .any(|pattern| pattern_matches(pattern, soname))
+    }
+}
+
+/// Match a name against a pattern, where `*` matches any run of
+/// characters and `?` matches any one character
+pub fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*`, if the rest fails to match
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_literals() {
+        assert!(pattern_matches("libc.so.6", "libc.so.6"));
+        assert!(!pattern_matches("libc.so.6", "libc.so.60"));
+        assert!(!pattern_matches("libc.so.6", "libc.so"));
+        assert!(pattern_matches("", ""));
+        assert!(!pattern_matches("", "libc.so.6"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(pattern_matches("libGL*", "libGL.so.1"));
+        assert!(pattern_matches("libGL*", "libGL"));
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches(
+            "libnvidia-*.so.*",
+            "libnvidia-glcore.so.470.82"
+        ));
+        assert!(!pattern_matches("libnvidia-*.so.*", "libnvidia-glcore.so"));
+        assert!(pattern_matches("libc.so.?", "libc.so.6"));
+        assert!(!pattern_matches("libc.so.?", "libc.so."));
+        assert!(!pattern_matches("libc.so.?", "libc.so.66"));
+    }
+
+    #[test]
+    fn matches_unversioned_names() {
+        assert!(pattern_matches("libGL.so*", "libGL.so"));
+        assert!(pattern_matches("libGL.so*", "libGL.so.1"));
+        assert!(!pattern_matches("libGL.so*", "libGLX.so.0"));
+        // A versioned pattern misses the names `-dev` packages add
+        assert!(!pattern_matches("libGL.so.*", "libGL.so"));
+    }
+
+    #[test]
+    fn shipped_policy_covers_unversioned_names() {
+        let policy: Policy = serde_yaml::from_str(include_str!("../policy.vcs.yaml")).unwrap();
+        for soname in &[
+            "libc.so",
+            "libc.so.6",
+            "libm.so",
+            "libGL.so",
+            "libEGL.so",
+            "libvulkan.so",
+            "libvulkan.so.1",
+            "libgbm.so",
+            "libdrm.so",
+            "libdrm_amdgpu.so.1",
+        ] {
+            assert!(
+                policy.never_bundle(soname).is_some(),
+                "{} should never be bundled",
+                soname
+            );
+        }
+        assert!(policy.never_bundle("libmount.so.1").is_none());
+    }
+
+    #[test]
+    fn wildcards_backtrack() {
+        // The first `.so` isn't the one the pattern needs
+        assert!(pattern_matches("*.so.1", "libfoo.so.so.1"));
+        assert!(pattern_matches("*a*b", "xaxxab"));
+        assert!(!pattern_matches("*a*b", "xaxxa"));
+        assert!(pattern_matches("**x", "abx"));
+    }
+}
@@ -0,0 +1,81 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The directory holding the profiles installed with bundle-gen
+pub const DEFAULT_PROFILE_DIR: &str = "/usr/local/share/bundle-gen/profiles";
+/// The environment variable that can add extra profile directories
+pub const PROFILE_PATH_ENV: &str = "BUNDLE_GEN_PROFILE_PATH";
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error("IO error opening profile {}: {1}", .0.to_string_lossy())]
+    Io(PathBuf, std::io::Error),
+    #[error("YAML parse error in profile {}: {1}", .0.to_string_lossy())]
+    Yaml(PathBuf, serde_yaml::Error),
+    #[error("no target profile named {0} could be found")]
+    NotFound(String),
+}
+
+/// A release of the VCS OS that bundles can target
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+pub struct TargetProfile {
+    pub name: String,
+    pub description: Option<String>,
+    /// The library cache describing the base system, relative to the
+    /// profile file
+    pub base_libraries: PathBuf,
+    /// The oldest OS version bundles built with this profile can run on
+    pub minimum_os_version: Option<String>,
+    /// The policy for bundling dependencies, relative to the profile file
+    pub policy: Option<PathBuf>,
+    /// The architecture bundled ELF files must be built for, like `x86_64`
+    pub architecture: Option<String>,
+    /// The dynamic loader bundled executables must request
+    pub interpreter: Option<String>,
+}
+
+impl TargetProfile {
+    /// Load a profile from a file, resolving its paths relative to the
+    /// file's location.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TargetProfile, ProfileError> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| ProfileError::Io(path.to_path_buf(), e))?;
+        let mut profile: TargetProfile =
+            serde_yaml::from_reader(file).map_err(|e| ProfileError::Yaml(path.to_path_buf(), e))?;
+        if let Some(parent) = path.parent() {
+            profile.base_libraries = parent.join(&profile.base_libraries);
+            profile.policy = profile.policy.map(|policy| parent.join(policy));
+        }
+        Ok(profile)
+    }
+
+    /// Find the profile with the given name, searching first the given
+    /// directories, then those listed in `BUNDLE_GEN_PROFILE_PATH`, and
+    /// finally [`DEFAULT_PROFILE_DIR`].
+    pub fn find(name: &str, dirs: &[PathBuf]) -> Result<TargetProfile, ProfileError> {
+        let env_dirs = env::var_os(PROFILE_PATH_ENV)
+            .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+            .unwrap_or_default();
+        for dir in dirs
+            .iter()
+            .chain(env_dirs.iter())
+            .chain(std::iter::once(&PathBuf::from(DEFAULT_PROFILE_DIR)))
+        {
+            let candidate = dir.join(format!("{}.yaml", name));
+            if candidate.exists() {
+                return TargetProfile::load(candidate);
+            }
+        }
+        Err(ProfileError::NotFound(name.to_string()))
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::generate::Stage;
+use crate::profile::TargetProfile;
+
+/// Why a file was placed in a bundle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOrigin {
+    /// Listed under `Executables`
+    Executable,
+    /// Listed under `Libraries`
+    Library,
+    /// Listed under `Resources`
+    Resource,
+    /// Found automatically while resolving library dependencies
+    Dependency,
+    /// Named by another file, which probably loads it with `dlopen`
+    Dlopen,
+    /// A plugin for a framework the bundle uses, like Qt
+    Plugin,
+    /// Given as the `RunnerPatch`
+    RunnerPatch,
+    /// Created by bundle-gen itself, like `run.sh`
+    Generated,
+}
+
+/// A single file in a generated bundle
+#[derive(Clone, Debug, Serialize)]
+pub struct BundledFile {
+    /// The path of the file inside the bundle
+    pub name: String,
+    /// Where the file was copied from, if it wasn't generated
+    pub source: Option<PathBuf>,
+    /// The size of the file in bytes as bundled, after any rewriting or
+    /// stripping, if it wasn't generated
+    pub size: Option<u64>,
+    /// Why the file was included
+    pub origin: FileOrigin,
+    /// For dependencies, the ELF file that needed this one
+    pub needed_by: Option<PathBuf>,
+    /// Whether the bundled copy had its runpath rewritten
+    pub runpath_rewritten: bool,
+    /// Whether the bundled copy had its debug info stripped
+    pub stripped: bool,
+}
+
+impl BundledFile {
+    /// A short human readable explanation of why the file was included
+    pub fn reason(&self) -> String {
+        match self.origin {
+            FileOrigin::Executable => "listed under Executables".to_string(),
+            FileOrigin::Library => "listed under Libraries".to_string(),
+            FileOrigin::Resource => "listed under Resources".to_string(),
+            FileOrigin::Dependency => match self.needed_by {
+                Some(ref elf) => format!("needed by {}", elf.to_string_lossy()),
+                None => "needed as a dependency".to_string(),
+            },
+            FileOrigin::Dlopen => match self.needed_by {
+                Some(ref elf) => {
+                    format!("probably loaded with dlopen by {}", elf.to_string_lossy())
+                }
+                None => "probably loaded with dlopen".to_string(),
+            },
+            FileOrigin::Plugin => match self.needed_by {
+                Some(ref lib) => format!("plugin for {}", lib.to_string_lossy()),
+                None => "plugin for a framework".to_string(),
+            },
+            FileOrigin::RunnerPatch => "given as RunnerPatch".to_string(),
+            FileOrigin::Generated => "generated by bundle-gen".to_string(),
+        }
+    }
+}
+
+/// How long a stage of the build took
+#[derive(Clone, Debug, Serialize)]
+pub struct StageTiming {
+    pub stage: Stage,
+    pub seconds: f64,
+}
+
+/// The outcome of a successful build
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildReport {
+    /// The generated bundle
+    pub bundle: PathBuf,
+    /// The version the bundle was given
+    pub version: String,
+    /// The size of the bundle in bytes
+    pub size: u64,
+    /// The SHA-256 hash of the bundle, in hex
+    pub sha256: String,
+    /// The OS release the bundle was built for, if one was chosen
+    pub target_profile: Option<TargetProfile>,
+    /// The debug info removed by stripping, if any was
+    pub debug_archive: Option<PathBuf>,
+    /// The build log, if it was written to a file
+    pub log_file: Option<PathBuf>,
+    /// Every file in the bundle, ordered by name
+    pub files: Vec<BundledFile>,
+    /// How long each stage of the build took, in order
+    pub timings: Vec<StageTiming>,
+    /// Any warnings issued during the build
+    pub warnings: Vec<String>,
+}
+
+impl BuildReport {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+pub(crate) fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
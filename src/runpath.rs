@@ -0,0 +1,165 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use goblin::elf::dynamic::{DT_RPATH, DT_RUNPATH};
+use log::trace;
+use thiserror::Error;
+
+use crate::FileEntry;
+
+#[derive(Debug, Error)]
+pub enum RunpathError {
+    #[error("IO error while rewriting the runpath of {}: {1}", .0.to_string_lossy())]
+    Io(PathBuf, std::io::Error),
+    #[error("unable to run patchelf; is it installed? {0}")]
+    Patchelf(std::io::Error),
+    #[error("patchelf failed to rewrite {}: {1}", .0.to_string_lossy())]
+    Failed(PathBuf, String),
+    #[error("{} doesn't parse after rewriting its runpath: {1}", .0.to_string_lossy())]
+    Elf(PathBuf, goblin::error::Error),
+    #[error("{} has runpath {1:?} after rewriting, not {2:?}", .0.to_string_lossy())]
+    Mismatch(PathBuf, Option<String>, String),
+}
+
+/// The DT_RUNPATH that lets a file at the given bundle entry find the
+/// bundle's libraries, like `$ORIGIN/../lib` for `bin/game`
+pub fn bundle_runpath(name: &str) -> String {
+    let dir = Path::new(name).parent().unwrap_or_else(|| Path::new(""));
+    match pathdiff::diff_paths("lib", dir) {
+        Some(rel) if rel.as_os_str().is_empty() => "$ORIGIN".to_string(),
+        Some(rel) => format!("$ORIGIN/{}", rel.to_string_lossy()),
+        None => "$ORIGIN".to_string(),
+    }
+}
+
+/// The runpath to give a file at the given bundle entry, which already
+/// has the given DT_RUNPATH or DT_RPATH. Entries relative to `$ORIGIN`,
+/// such as those letting plugins find their siblings, are kept after
+/// the bundle's `lib/`; others point outside the bundle, so are dropped.
+pub fn combined_runpath(name: &str, existing: Option<&str>) -> String {
+    let mut entries = vec![bundle_runpath(name)];
+    for entry in existing.into_iter().flat_map(|r| r.split(':')) {
+        let relative = entry.starts_with("$ORIGIN") || entry.starts_with("${ORIGIN}");
+        if relative && !entries.iter().any(|e| e == entry) {
+            entries.push(entry.to_string());
+        }
+    }
+    entries.join(":")
+}
+
+/// Whether a file is a dynamically linked ELF file
+fn is_dynamic_elf(buf: &[u8]) -> bool {
+    matches!(
+        goblin::Object::parse(buf),
+        Ok(goblin::Object::Elf(elf)) if elf.dynamic.is_some()
+    )
+}
+
+/// Read a file's DT_RUNPATH, or if it has none and `rpath` is set, its
+/// DT_RPATH
+fn read_runpath(path: &Path, buf: &[u8], rpath: bool) -> Result<Option<String>, RunpathError> {
+    let elf = goblin::elf::Elf::parse(buf).map_err(|e| RunpathError::Elf(path.to_path_buf(), e))?;
+    let find = |tag| {
+        elf.dynamic.as_ref().and_then(|d| {
+            d.dyns
+                .iter()
+                .find(|entry| entry.d_tag == tag)
+                .and_then(|entry| elf.dynstrtab.get_at(entry.d_val as usize))
+                .map(str::to_owned)
+        })
+    };
+    Ok(find(DT_RUNPATH).or_else(|| if rpath { find(DT_RPATH) } else { None }))
+}
+
+/// Copy a dynamically linked ELF file into the given directory and
+/// rewrite the copy's DT_RUNPATH so it finds the bundle's libraries
+/// wherever the bundle is installed, keeping any of its own entries
+/// relative to `$ORIGIN`, and checking the result still parses.
+/// Returns the entry for the copy, or `None` if the file isn't a
+/// dynamically linked ELF file and should be bundled unchanged.
+pub fn rewrite_runpath(
+    entry: &FileEntry,
+    scratch: &Path,
+    index: usize,
+) -> Result<Option<FileEntry>, RunpathError> {
+    let buf = fs::read(&entry.location).map_err(|e| RunpathError::Io(entry.location.clone(), e))?;
+    if !is_dynamic_elf(&buf) {
+        return Ok(None);
+    }
+
+    let copy = entry
+        .scratch_copy(scratch, index)
+        .map_err(|e| RunpathError::Io(scratch.to_path_buf(), e))?;
+    fs::copy(&entry.location, &copy).map_err(|e| RunpathError::Io(copy.clone(), e))?;
+
+    let existing = read_runpath(&entry.location, &buf, true)?;
+    let runpath = combined_runpath(&entry.name, existing.as_deref());
+    trace!("Setting runpath of {} to {}", entry.name, runpath);
+    let output = Command::new("patchelf")
+        .arg("--set-rpath")
+        .arg(&runpath)
+        .arg(&copy)
+        .output()
+        .map_err(RunpathError::Patchelf)?;
+    if !output.status.success() {
+        return Err(RunpathError::Failed(
+            entry.location.clone(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let patched = fs::read(&copy).map_err(|e| RunpathError::Io(copy.clone(), e))?;
+    let found = read_runpath(&copy, &patched, false)?;
+    if found.as_deref() != Some(runpath.as_str()) {
+        return Err(RunpathError::Mismatch(
+            entry.location.clone(),
+            found,
+            runpath,
+        ));
+    }
+
+    Ok(Some(FileEntry {
+        location: copy,
+        name: entry.name.clone(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_runpath_keeps_origin_entries() {
+        assert_eq!(combined_runpath("bin/game", None), "$ORIGIN/../lib");
+        assert_eq!(
+            combined_runpath(
+                "lib/plugins/libp.so",
+                Some("$ORIGIN:/usr/lib/foo:${ORIGIN}/extra")
+            ),
+            "$ORIGIN/..:$ORIGIN:${ORIGIN}/extra"
+        );
+        // The bundle's own entry isn't repeated
+        assert_eq!(
+            combined_runpath("bin/game", Some("$ORIGIN/../lib:/opt/game/lib")),
+            "$ORIGIN/../lib"
+        );
+    }
+
+    #[test]
+    fn runpath_points_at_lib() {
+        assert_eq!(bundle_runpath("bin/game"), "$ORIGIN/../lib");
+        assert_eq!(bundle_runpath("lib/libfoo.so.1"), "$ORIGIN");
+        assert_eq!(bundle_runpath("game"), "$ORIGIN/lib");
+        assert_eq!(bundle_runpath("lib/plugins/libp.so"), "$ORIGIN/..");
+        assert_eq!(
+            bundle_runpath("res/plugins/qt5/platforms/libqxcb.so"),
+            "$ORIGIN/../../../../lib"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::Elf;
+use log::trace;
+use thiserror::Error;
+use zip::ZipWriter;
+
+use crate::audit;
+use crate::FileEntry;
+
+#[derive(Debug, Error)]
+pub enum StripError {
+    #[error("IO error while stripping {}: {1}", .0.to_string_lossy())]
+    Io(PathBuf, std::io::Error),
+    #[error("unable to run objcopy; is it installed? {0}")]
+    Objcopy(std::io::Error),
+    #[error("objcopy failed to strip {}: {1}", .0.to_string_lossy())]
+    Failed(PathBuf, String),
+    #[error("unable to write debug archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// The debug info removed from a file in the bundle
+#[derive(Clone, Debug)]
+pub struct DebugFile {
+    /// The stripped file's name in the bundle
+    pub name: String,
+    /// The stripped file's GNU build ID, in hex
+    pub build_id: Option<String>,
+    /// Where the debug info was written
+    pub location: PathBuf,
+}
+
+impl DebugFile {
+    /// The name of the debug info in the debug archive. Files with a
+    /// build ID use the `.build-id/ab/cdef.debug` layout debuggers look
+    /// for; others are named after the stripped file.
+    pub fn archive_name(&self) -> String {
+        match self.build_id {
+            Some(ref id) if id.len() > 2 => format!(".build-id/{}/{}.debug", &id[..2], &id[2..]),
+            _ => format!("{}.debug", self.name),
+        }
+    }
+}
+
+fn build_id(elf: &Elf, buf: &[u8]) -> Option<String> {
+    elf.iter_note_sections(buf, None)
+        .into_iter()
+        .chain(elf.iter_note_headers(buf))
+        .flatten()
+        .filter_map(Result::ok)
+        .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name == "GNU")
+        .map(|note| note.desc.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn objcopy(args: &[&std::ffi::OsStr], location: &Path) -> Result<(), StripError> {
+    let output = Command::new("objcopy")
+        .args(args)
+        .output()
+        .map_err(StripError::Objcopy)?;
+    if !output.status.success() {
+        return Err(StripError::Failed(
+            location.to_path_buf(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Copy an ELF file into the given directory with its debug info and
+/// unneeded symbols stripped, saving the debug info alongside it.
+/// Returns the entry for the stripped copy and where its debug info
+/// went, or `None` if the file isn't an ELF file with debug info and
+/// should be bundled unchanged.
+pub fn strip_debug(
+    entry: &FileEntry,
+    scratch: &Path,
+    index: usize,
+) -> Result<Option<(FileEntry, DebugFile)>, StripError> {
+    let buf = fs::read(&entry.location).map_err(|e| StripError::Io(entry.location.clone(), e))?;
+    let elf = match goblin::Object::parse(&buf) {
+        Ok(goblin::Object::Elf(elf)) if audit::has_debug_info(&elf) => elf,
+        _ => return Ok(None),
+    };
+
+    let stripped = entry
+        .scratch_copy(scratch, index)
+        .map_err(|e| StripError::Io(scratch.to_path_buf(), e))?;
+    let mut debug = stripped.clone().into_os_string();
+    debug.push(".debug");
+    let debug = PathBuf::from(debug);
+
+    trace!("Stripping {}", entry.name);
+    objcopy(
+        &[
+            "--only-keep-debug".as_ref(),
+            entry.location.as_ref(),
+            debug.as_ref(),
+        ],
+        &entry.location,
+    )?;
+    objcopy(
+        &[
+            "--strip-unneeded".as_ref(),
+            entry.location.as_ref(),
+            stripped.as_ref(),
+        ],
+        &entry.location,
+    )?;
+
+    Ok(Some((
+        FileEntry {
+            location: stripped,
+            name: entry.name.clone(),
+        },
+        DebugFile {
+            name: entry.name.clone(),
+            build_id: build_id(&elf, &buf),
+            location: debug,
+        },
+    )))
+}
+
+/// Write the debug info removed from a bundle's files into a zip archive.
+/// Copies of the same file share a build ID, so only the first is kept.
+pub fn write_debug_archive<P: AsRef<Path>>(path: P, files: &[DebugFile]) -> Result<(), StripError> {
+    let path = path.as_ref();
+    let f = File::create(path).map_err(|e| StripError::Io(path.to_path_buf(), e))?;
+    let mut zf = ZipWriter::new(f);
+    let mut written = BTreeSet::new();
+    for file in files {
+        let name = file.archive_name();
+        if !written.insert(name.clone()) {
+            continue;
+        }
+        zf.start_file(name, zip::write::FileOptions::default())?;
+        let mut debug =
+            File::open(&file.location).map_err(|e| StripError::Io(file.location.clone(), e))?;
+        std::io::copy(&mut debug, &mut zf).map_err(|e| StripError::Io(path.to_path_buf(), e))?;
+    }
+    zf.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use zip::ZipArchive;
+
+    #[test]
+    fn debug_archive_keeps_one_copy_per_build_id() {
+        let dir = TempDir::new("bundle-gen-test").unwrap();
+        let debug = dir.path().join("libfoo.so.1.debug");
+        fs::write(&debug, b"debug info").unwrap();
+        let file = |name: &str| DebugFile {
+            name: name.to_string(),
+            build_id: Some("abcdef".to_string()),
+            location: debug.clone(),
+        };
+
+        let archive = dir.path().join("game.debug.zip");
+        write_debug_archive(&archive, &[file("lib/libfoo.so.1"), file("lib/libfoo.so")]).unwrap();
+        let za = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        assert_eq!(
+            za.file_names().collect::<Vec<_>>(),
+            [".build-id/ab/cdef.debug"]
+        );
+    }
+}
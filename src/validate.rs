@@ -0,0 +1,224 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+/// The longest single path component the console's filesystem accepts
+pub const MAX_COMPONENT_LEN: usize = 255;
+/// The longest entry name we accept, leaving room for the install prefix
+pub const MAX_NAME_LEN: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum EntryNameError {
+    #[error("bundle entry name is empty")]
+    Empty,
+    #[error("bundle entry {0} is an absolute path")]
+    Absolute(String),
+    #[error("bundle entry {0} refers to a parent directory")]
+    ParentReference(String),
+    #[error("bundle entry {0} contains control characters")]
+    ControlCharacter(String),
+    #[error(
+        "bundle entry {0} has a component longer than {} bytes",
+        MAX_COMPONENT_LEN
+    )]
+    ComponentTooLong(String),
+    #[error("bundle entry {0} is longer than {} bytes", MAX_NAME_LEN)]
+    TooLong(String),
+    #[error("bundle entries {0} and {1} differ only by case")]
+    CaseCollision(String, String),
+}
+
+/// Normalise the destination name of a bundle entry, rejecting names
+/// that would extract outside the bundle or can't be represented on the
+/// console.
+///
+/// Backslashes are treated as directory separators, and empty or `.`
+/// components are dropped, so `./res\\a//b` becomes `res/a/b`.
+pub fn normalise_entry_name(name: &str) -> Result<String, EntryNameError> {
+    if name.chars().any(char::is_control) {
+        return Err(EntryNameError::ControlCharacter(
+            name.escape_debug().to_string(),
+        ));
+    }
+
+    let unified = name.replace('\\', "/");
+    if unified.starts_with('/') {
+        return Err(EntryNameError::Absolute(name.to_string()));
+    }
+
+    let mut parts = Vec::new();
+    for part in unified.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return Err(EntryNameError::ParentReference(name.to_string())),
+            _ => {
+                if part.len() > MAX_COMPONENT_LEN {
+                    return Err(EntryNameError::ComponentTooLong(name.to_string()));
+                }
+                parts.push(part);
+            }
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(EntryNameError::Empty);
+    }
+
+    let normalised = parts.join("/");
+    if normalised.len() > MAX_NAME_LEN {
+        return Err(EntryNameError::TooLong(name.to_string()));
+    }
+
+    Ok(normalised)
+}
+
+/// Check that no two entry names (or their parent directories) would
+/// collide on a case-insensitive filesystem.
+pub fn check_case_collisions<'a, I>(names: I) -> Result<(), EntryNameError>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut seen = BTreeMap::<String, String>::new();
+    for name in names {
+        // Directories are implied by their contents, so check every prefix
+        let mut prefix = String::new();
+        for part in name.split('/') {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(part);
+            let folded = prefix.to_lowercase();
+            match seen.get(&folded) {
+                Some(other) if *other != prefix => {
+                    return Err(EntryNameError::CaseCollision(other.clone(), prefix));
+                }
+                Some(_) => {}
+                None => {
+                    seen.insert(folded, prefix.clone());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalises_separators_and_dots() {
+        assert_eq!(normalise_entry_name("./res\\a//b").unwrap(), "res/a/b");
+        assert_eq!(normalise_entry_name("bin/game").unwrap(), "bin/game");
+    }
+
+    #[test]
+    fn rejects_parent_references() {
+        assert!(matches!(
+            normalise_entry_name("res/../../etc/passwd"),
+            Err(EntryNameError::ParentReference(_))
+        ));
+        assert!(matches!(
+            normalise_entry_name("..\\lib"),
+            Err(EntryNameError::ParentReference(_))
+        ));
+        // Only a whole component is a parent reference
+        assert_eq!(normalise_entry_name("res/..a").unwrap(), "res/..a");
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert!(matches!(
+            normalise_entry_name("/usr/lib/libc.so.6"),
+            Err(EntryNameError::Absolute(_))
+        ));
+        assert!(matches!(
+            normalise_entry_name("\\lib"),
+            Err(EntryNameError::Absolute(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        assert!(matches!(
+            normalise_entry_name("res/a\nb"),
+            Err(EntryNameError::ControlCharacter(_))
+        ));
+        assert!(matches!(
+            normalise_entry_name("res/\u{7f}"),
+            Err(EntryNameError::ControlCharacter(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        assert!(matches!(
+            normalise_entry_name(""),
+            Err(EntryNameError::Empty)
+        ));
+        assert!(matches!(
+            normalise_entry_name("./"),
+            Err(EntryNameError::Empty)
+        ));
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        let component = "a".repeat(MAX_COMPONENT_LEN);
+        assert!(normalise_entry_name(&component).is_ok());
+        assert!(matches!(
+            normalise_entry_name(&format!("res/{}a", component)),
+            Err(EntryNameError::ComponentTooLong(_))
+        ));
+
+        let name = [component.as_str(); 4].join("/");
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(normalise_entry_name(&name).is_ok());
+        let name = [component.as_str(); 5].join("/");
+        assert!(matches!(
+            normalise_entry_name(&name),
+            Err(EntryNameError::TooLong(_))
+        ));
+        // Dropped components don't count towards the length
+        let padded = format!("{}{}", "./".repeat(MAX_NAME_LEN), component);
+        assert!(normalise_entry_name(&padded).is_ok());
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_distinct_names() {
+        assert!(check_case_collisions(&names(&["bin/game", "lib/libfoo.so", "res/game"])).is_ok());
+        // The same directory may hold many entries
+        assert!(check_case_collisions(&names(&["res/a", "res/b", "res/a"])).is_ok());
+    }
+
+    #[test]
+    fn finds_case_collisions() {
+        match check_case_collisions(&names(&["res/Readme", "res/README"])) {
+            Err(EntryNameError::CaseCollision(a, b)) => {
+                assert_eq!(a, "res/Readme");
+                assert_eq!(b, "res/README");
+            }
+            r => panic!("expected a collision, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn finds_directory_case_collisions() {
+        match check_case_collisions(&names(&["res/data/a", "Res/other"])) {
+            Err(EntryNameError::CaseCollision(a, b)) => {
+                assert_eq!(a, "res");
+                assert_eq!(b, "Res");
+            }
+            r => panic!("expected a collision, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright 2022 Collabora, Ltd.
+ *
+ * SPDX-License-Identifier: MIT
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::path::Path;
+
+use atari_bundle::{BundleConfig, BundleError};
+use log::trace;
+use thiserror::Error;
+use zip::ZipArchive;
+
+use crate::audit::{self, ElfAudit};
+use crate::config::BundleSpec;
+use crate::ldcache::{self, LdError, ResolveOptions};
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("unable to reopen bundle: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unable to read bundle archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("unable to read bundle configuration: {0}")]
+    Bundle(#[from] BundleError),
+    #[error("unable to load base system libraries: {0}")]
+    Ld(#[from] LdError),
+    #[error("{}", .0.join("; "))]
+    Problems(Vec<String>),
+}
+
+/// The first word of a startup command, which is what will be run
+fn command_target(command: &str) -> String {
+    shell_words::split(command)
+        .ok()
+        .and_then(|parts| parts.into_iter().next())
+        .unwrap_or_else(|| command.to_string())
+}
+
+/// What verifying a bundle found, besides problems that fail the build
+#[derive(Clone, Debug, Default)]
+pub struct Verified {
+    /// The hardening audit of every ELF file in the bundle
+    pub audits: Vec<ElfAudit>,
+    /// Checks that couldn't be made
+    pub warnings: Vec<String>,
+}
+
+/// Reopen a freshly written bundle and check that it will work on the
+/// console: that its configuration can be read, that everything it
+/// starts exists and is executable, that every library any ELF file in
+/// it needs is either bundled in `lib/` or part of the base system, and
+/// that those libraries provide every symbol version it needs. Every
+/// ELF file is also audited, and findings the spec or policy lists
+/// under `HardeningFailures` are treated as problems.
+pub fn verify_bundle<P: AsRef<Path>>(
+    path: P,
+    spec: &BundleSpec,
+    options: &ResolveOptions,
+) -> Result<Verified, VerifyError> {
+    let mut za = ZipArchive::new(File::open(path.as_ref())?)?;
+    let config = BundleConfig::from_archive(&mut za)?;
+
+    let mut problems = Vec::new();
+    let mut required = Vec::new();
+    if config.bundle.launcher.is_none() {
+        if let Some(ref exec) = config.bundle.exec {
+            required.push(command_target(exec));
+        }
+    }
+    if let Some(ref launcher_exec) = config.bundle.launcher_exec {
+        required.push(command_target(launcher_exec));
+    }
+    // The scripts we generate wrap the commands from the spec
+    if za.file_names().any(|n| n == "run.sh") {
+        if let Some(ref exec) = spec.exec {
+            required.push(command_target(exec));
+        }
+    }
+    if za.file_names().any(|n| n == "launch.sh") {
+        if let Some(ref launcher_exec) = spec.launcher_exec {
+            required.push(command_target(launcher_exec));
+        }
+    }
+
+    for target in required {
+        trace!("Verifying {} is executable", target);
+        match za.by_name(&target) {
+            Ok(zf) => {
+                if zf.unix_mode().unwrap_or(0) & 0o111 == 0 {
+                    problems.push(format!("{} is not executable", target));
+                }
+            }
+            Err(zip::result::ZipError::FileNotFound) => {
+                problems.push(format!("{} is started, but is not in the bundle", target));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let bundled_libs = za
+        .file_names()
+        .filter_map(|n| n.strip_prefix("lib/"))
+        .filter(|n| !n.is_empty() && !n.contains('/'))
+        .map(str::to_string)
+        .collect::<BTreeSet<_>>();
+
+    let base_deps = options.load_base_deps()?;
+    let policy = options.load_policy()?;
+    let elves = audit::elf_files(&mut za)?;
+
+    // The symbol versions each bundled library provides
+    let mut bundled_versions = BTreeMap::new();
+    for (name, buf) in elves.iter() {
+        if let Some(lib) = name
+            .strip_prefix("lib/")
+            .filter(|n| bundled_libs.contains(*n))
+        {
+            if let Ok(versions) = ldcache::elf_symbol_versions(buf) {
+                bundled_versions.insert(lib.to_string(), versions.defined);
+            }
+        }
+    }
+
+    // Base libraries needed with versions that can't be checked
+    let mut unchecked = BTreeMap::<String, BTreeSet<String>>::new();
+    for (name, buf) in elves.iter() {
+        trace!("Verifying dependencies of {}", name);
+        match ldcache::elf_deps(buf) {
+            Ok(deps) => {
+                for dep in deps {
+                    // The policy may deliberately leave libraries out
+                    let excused =
+                        policy.allows_missing(&dep) || policy.never_bundle(&dep).is_some();
+                    if !bundled_libs.contains(&dep) && !base_deps.contains(&dep) && !excused {
+                        problems.push(format!("{} needs {}, which can't be found", name, dep));
+                    }
+                }
+            }
+            Err(e) => {
+                problems.push(format!("{} can't be parsed: {}", name, e));
+                continue;
+            }
+        }
+
+        trace!("Verifying symbol versions needed by {}", name);
+        let needed = ldcache::elf_symbol_versions(buf)?.needed;
+        for (soname, versions) in needed {
+            // As with the loader, libraries that define no symbol
+            // versions can't be checked
+            let provided = if bundled_libs.contains(&soname) {
+                bundled_versions.get(&soname).filter(|v| !v.is_empty())
+            } else {
+                match base_deps.get(&soname) {
+                    Some(l) if l.symbol_versions.is_empty() => {
+                        unchecked.entry(soname).or_default().extend(versions);
+                        continue;
+                    }
+                    Some(l) => Some(&l.symbol_versions),
+                    None => None,
+                }
+            };
+            if let Some(provided) = provided {
+                for version in versions.difference(provided) {
+                    problems.push(format!(
+                        "{} needs version {} of {}, which the target doesn't provide",
+                        name, version, soname
+                    ));
+                }
+            }
+        }
+    }
+
+    let failures = policy
+        .hardening_failures
+        .iter()
+        .chain(spec.build.hardening_failures.iter().flatten())
+        .collect::<BTreeSet<_>>();
+    let audits = elves
+        .iter()
+        .filter_map(|(name, buf)| audit::audit_elf(name, buf))
+        .collect::<Vec<_>>();
+    for audit in audits.iter() {
+        for finding in audit.findings.iter().filter(|f| failures.contains(f)) {
+            problems.push(format!("{}: {}", audit.name, finding));
+        }
+    }
+
+    let mut warnings = Vec::new();
+    if !unchecked.is_empty() {
+        warnings.push(format!(
+            "the base library profile doesn't list symbol versions, so the versions \
+             needed from these base libraries weren't checked: {}",
+            unchecked
+                .into_iter()
+                .map(|(soname, versions)| format!(
+                    "{} ({})",
+                    soname,
+                    versions.into_iter().collect::<Vec<_>>().join(", ")
+                ))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(Verified { audits, warnings })
+    } else {
+        Err(VerifyError::Problems(problems))
+    }
+}